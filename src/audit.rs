@@ -0,0 +1,303 @@
+use crate::config::Action;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// `audit` section of credentials.yaml.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Size at which audit.jsonl is rotated
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_size_mb() -> u64 {
+    10
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            max_size_mb: default_max_size_mb(),
+            keep: default_keep(),
+        }
+    }
+}
+
+/// A routing decision and its outcome.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub run_id: String,
+    pub message_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    /// Name of the rule that decided, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// `None` when the message was kept
+    pub action: Option<Action>,
+    /// Set when `action` was reverted by `undo`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub undo: bool,
+    /// Alias labels added to a kept message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    pub result: AuditResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditResult {
+    Kept,
+    Applied,
+    Failed,
+}
+
+/// Appends entries to audit.jsonl, rotating it when it grows too large.
+pub struct AuditLog {
+    path: PathBuf,
+    config: AuditConfig,
+}
+
+impl AuditLog {
+    pub fn new<P: Into<PathBuf>>(path: P, config: AuditConfig) -> Self {
+        Self {
+            path: path.into(),
+            config,
+        }
+    }
+
+    /// Writes an entry; failures are logged rather than interrupting processing.
+    pub fn record(&self, entry: &AuditEntry) {
+        if !self.config.enabled {
+            return;
+        }
+        if let Err(e) = self.try_record(entry) {
+            warn!("Failed to write audit log: {:#}", e);
+        }
+    }
+
+    fn try_record(&self, entry: &AuditEntry) -> Result<()> {
+        rotate(
+            &self.path,
+            self.config.max_size_mb * 1024 * 1024,
+            self.config.keep,
+        )?;
+
+        let line = serde_json::to_string(entry).context("Failed to serialize audit entry")?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("Failed to open audit log")?;
+        writeln!(file, "{}", line).context("Failed to write audit log")
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Renames `path` to `path.1`, shifting older files up and dropping those beyond `keep`,
+/// once `path` has reached `max_bytes`.
+pub fn rotate(path: &Path, max_bytes: u64, keep: usize) -> Result<()> {
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(()),
+    };
+    if max_bytes == 0 || size < max_bytes {
+        return Ok(());
+    }
+
+    if keep == 0 {
+        return fs::remove_file(path).context("Failed to remove audit log");
+    }
+
+    let _ = fs::remove_file(rotated_path(path, keep));
+    for index in (1..keep).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, index + 1))
+                .context("Failed to rotate audit log")?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1)).context("Failed to rotate audit log")
+}
+
+/// Entries from the rotated files and the current log, oldest first.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<AuditEntry>> {
+    let path = path.as_ref();
+
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|p| p.exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_path_buf());
+
+    let mut entries = Vec::new();
+    for file in files.iter().filter(|p| p.exists()) {
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("Failed to read audit log {:?}", file))?;
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            entries.push(serde_json::from_str(line).context("Failed to parse audit log")?);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Filter for `gmail_router audit`. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub address: Option<String>,
+    pub from: Option<String>,
+    /// `keep` matches kept messages
+    pub action: Option<String>,
+    pub message_id: Option<String>,
+    pub run_id: Option<String>,
+    pub failed: bool,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        let action = entry.action.map_or("keep".to_string(), |a| a.to_string());
+
+        self.since.is_none_or(|since| entry.at >= since)
+            && self
+                .address
+                .as_ref()
+                .is_none_or(|address| entry.recipients.contains(address))
+            && self.from.as_ref().is_none_or(|pattern| {
+                entry
+                    .from
+                    .as_deref()
+                    .is_some_and(|from| crate::rules::glob_match(pattern, from))
+            })
+            && self.action.as_ref().is_none_or(|wanted| *wanted == action)
+            && self
+                .message_id
+                .as_ref()
+                .is_none_or(|id| entry.message_id == *id || entry.thread_id.as_ref() == Some(id))
+            && self
+                .run_id
+                .as_ref()
+                .is_none_or(|run_id| entry.run_id == *run_id)
+            && (!self.failed || entry.result == AuditResult::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message_id: &str, action: Option<Action>, result: AuditResult) -> AuditEntry {
+        AuditEntry {
+            at: "2024-06-12T08:30:00Z".parse().unwrap(),
+            run_id: "run-1".to_string(),
+            message_id: message_id.to_string(),
+            thread_id: Some(format!("t-{}", message_id)),
+            subject: Some("Deals".to_string()),
+            from: Some("news@shop.com".to_string()),
+            recipients: vec!["shop".to_string()],
+            rule: None,
+            action,
+            undo: false,
+            labels: Vec::new(),
+            result,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_rotation_and_load() {
+        let path = std::env::temp_dir().join(format!(
+            "gmail_router_test_audit_{}.jsonl",
+            std::process::id()
+        ));
+        let cleanup = || {
+            for p in [path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)] {
+                let _ = fs::remove_file(p);
+            }
+        };
+        cleanup();
+
+        let log = AuditLog::new(&path, AuditConfig::default());
+        for id in ["m1", "m2", "m3", "m4"] {
+            log.record(&entry(id, Some(Action::Spam), AuditResult::Applied));
+            // Rotate after every entry, keeping two old files
+            rotate(&path, 1, 2).unwrap();
+        }
+        log.record(&entry("m5", None, AuditResult::Kept));
+
+        let ids: Vec<_> = load(&path)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.message_id)
+            .collect();
+        assert!(!rotated_path(&path, 3).exists());
+        cleanup();
+
+        assert_eq!(ids, vec!["m3", "m4", "m5"]);
+    }
+
+    #[test]
+    fn test_query() {
+        let kept = entry("m1", None, AuditResult::Kept);
+        let failed = entry("m2", Some(Action::Delete), AuditResult::Failed);
+
+        let query = AuditQuery {
+            action: Some("keep".to_string()),
+            ..Default::default()
+        };
+        assert!(query.matches(&kept));
+        assert!(!query.matches(&failed));
+
+        let query = AuditQuery {
+            failed: true,
+            from: Some("*@shop.com".to_string()),
+            ..Default::default()
+        };
+        assert!(!query.matches(&kept));
+        assert!(query.matches(&failed));
+
+        let query = AuditQuery {
+            message_id: Some("t-m1".to_string()),
+            address: Some("shop".to_string()),
+            ..Default::default()
+        };
+        assert!(query.matches(&kept));
+        assert!(!query.matches(&failed));
+    }
+}
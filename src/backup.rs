@@ -0,0 +1,269 @@
+use crate::config::{config_dir, Action};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// `backup` section of credentials.yaml.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub format: BackupFormat,
+    /// Archive folder; `backup` in the configuration folder when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Actions that back the message up first
+    #[serde(default = "default_actions")]
+    pub actions: Vec<Action>,
+}
+
+fn default_actions() -> Vec<Action> {
+    vec![Action::Delete]
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: BackupFormat::default(),
+            path: None,
+            actions: default_actions(),
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn root(&self) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| config_dir().join("backup"))
+    }
+
+    pub fn applies_to(&self, action: Action) -> bool {
+        self.enabled && self.actions.contains(&action)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupFormat {
+    /// One Maildir per alias and month
+    #[default]
+    Maildir,
+    /// One mbox file per alias and month
+    Mbox,
+}
+
+/// Stores a raw RFC 822 message under `root/<alias>/<YYYY-MM>` and returns its location.
+pub fn store(
+    root: &Path,
+    format: BackupFormat,
+    alias: &str,
+    received: DateTime<Utc>,
+    message_id: &str,
+    raw: &[u8],
+) -> Result<PathBuf> {
+    let alias_dir = root.join(sanitize(alias));
+    let month = received.format("%Y-%m").to_string();
+
+    match format {
+        BackupFormat::Maildir => {
+            let maildir = alias_dir.join(month);
+            for sub in ["tmp", "new", "cur"] {
+                fs::create_dir_all(maildir.join(sub))
+                    .with_context(|| format!("Failed to create Maildir {:?}", maildir))?;
+            }
+
+            let name = format!(
+                "{}.{}.gmail_router",
+                received.timestamp(),
+                sanitize(message_id)
+            );
+            let tmp = maildir.join("tmp").join(&name);
+            let new = maildir.join("new").join(&name);
+            fs::write(&tmp, raw).with_context(|| format!("Failed to write {:?}", tmp))?;
+            fs::rename(&tmp, &new).with_context(|| format!("Failed to move {:?}", tmp))?;
+            Ok(new)
+        }
+        BackupFormat::Mbox => {
+            fs::create_dir_all(&alias_dir)
+                .with_context(|| format!("Failed to create {:?}", alias_dir))?;
+            let path = alias_dir.join(format!("{}.mbox", month));
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {:?}", path))?;
+            file.write_all(&mbox_entry(received, raw))
+                .with_context(|| format!("Failed to write {:?}", path))?;
+            Ok(path)
+        }
+    }
+}
+
+/// Keeps path components to characters that are safe in file names.
+fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '+' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    match cleaned.trim_matches('.') {
+        "" => "_".to_string(),
+        _ => cleaned,
+    }
+}
+
+/// A message in mboxrd format: a `From ` separator line, then the message with
+/// `From ` lines quoted by an extra `>`.
+fn mbox_entry(received: DateTime<Utc>, raw: &[u8]) -> Vec<u8> {
+    let mut entry = format!(
+        "From MAILER-DAEMON {}\n",
+        received.format("%a %b %e %H:%M:%S %Y")
+    )
+    .into_bytes();
+
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let unquoted = line.iter().position(|&b| b != b'>').unwrap_or(line.len());
+        if line[unquoted..].starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+    }
+    if !entry.ends_with(b"\n") {
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+/// Splits an mboxrd file into raw messages.
+pub fn parse_mbox(contents: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+
+    for line in contents.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(b"From ") {
+            messages.extend(current.take().map(trim_separator));
+            current = Some(Vec::new());
+            continue;
+        }
+        let Some(message) = current.as_mut() else {
+            continue;
+        };
+        let unquoted = line.iter().position(|&b| b != b'>').unwrap_or(line.len());
+        if unquoted > 0 && line[unquoted..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(line);
+        }
+    }
+    messages.extend(current.map(trim_separator));
+
+    messages
+}
+
+/// Drops the blank line that separates mbox entries.
+fn trim_separator(mut message: Vec<u8>) -> Vec<u8> {
+    if message.ends_with(b"\n\n") {
+        message.pop();
+    }
+    message
+}
+
+/// Raw messages in a Maildir message file, an mbox file, or any folder of them.
+pub fn read_messages(path: &Path) -> Result<Vec<Vec<u8>>> {
+    Ok(read_named_messages(path)?
+        .into_iter()
+        .map(|(_, raw)| raw)
+        .collect())
+}
+
+/// Like [`read_messages`], with a name for each message: its file path, followed
+/// by `#n` for the n-th message of an mbox file.
+pub fn read_named_messages(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    if path.is_dir() {
+        let mut entries: Vec<_> = fs::read_dir(path)
+            .with_context(|| format!("Failed to read {:?}", path))?
+            .collect::<std::io::Result<_>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut messages = Vec::new();
+        for entry in entries {
+            // Maildir's tmp holds messages that were never completely written
+            if entry.file_name() == "tmp" {
+                continue;
+            }
+            messages.extend(read_named_messages(&entry.path())?);
+        }
+        return Ok(messages);
+    }
+
+    let contents = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let name = path.display().to_string();
+    if path.extension().is_some_and(|ext| ext == "mbox") {
+        Ok(parse_mbox(&contents)
+            .into_iter()
+            .enumerate()
+            .map(|(index, raw)| (format!("{}#{}", name, index + 1), raw))
+            .collect())
+    } else {
+        Ok(vec![(name, contents)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] =
+        b"From: shop@example.com\nSubject: hi\n\nFrom now on\n>From the archive\nbye\n";
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "gmail_router_test_backup_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn test_mbox_round_trip() {
+        let at = "2024-06-12T08:30:00Z".parse().unwrap();
+        let mut mbox = mbox_entry(at, MESSAGE);
+        assert!(mbox.starts_with(b"From MAILER-DAEMON Wed Jun 12 08:30:00 2024\n"));
+        mbox.extend(mbox_entry(at, b"Subject: second"));
+
+        let messages = parse_mbox(&mbox);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], MESSAGE);
+        assert_eq!(messages[1], b"Subject: second\n");
+    }
+
+    #[test]
+    fn test_store_and_read() {
+        let at = "2024-06-12T08:30:00Z".parse().unwrap();
+
+        for format in [BackupFormat::Maildir, BackupFormat::Mbox] {
+            let root = temp_root(&format!("{:?}", format));
+            store(&root, format, "shop", at, "m1", MESSAGE).unwrap();
+            let path = store(&root, format, "../shop", at, "m/2", b"Subject: 2\n").unwrap();
+
+            assert!(path.starts_with(root.join(".._shop")));
+            let messages = read_messages(&root).unwrap();
+            fs::remove_dir_all(&root).unwrap();
+
+            assert_eq!(messages.len(), 2, "{:?}", format);
+            assert!(messages.contains(&MESSAGE.to_vec()));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Attempts after which a failing message is given up on.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Progress of the processing loop, kept in the runtime state.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Checkpoint {
+    /// Start of the last successful cycle; the next one lists mail since then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed_until: Option<DateTime<Utc>>,
+    /// Messages (or threads) whose processing failed, by ID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failed: BTreeMap<String, FailedMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FailedMessage {
+    pub attempts: u32,
+    pub first_failed: DateTime<Utc>,
+    pub last_error: String,
+}
+
+impl Checkpoint {
+    /// Failed IDs not yet listed in `ids`, to be retried this cycle.
+    pub fn retries(&self, ids: &[String]) -> Vec<String> {
+        self.failed
+            .keys()
+            .filter(|id| !ids.contains(id))
+            .cloned()
+            .collect()
+    }
+
+    pub fn record_success(&mut self, id: &str) {
+        self.failed.remove(id);
+    }
+
+    /// Remembers a failure. Returns `false` once the message has failed
+    /// [`MAX_ATTEMPTS`] times and is no longer retried.
+    pub fn record_failure(&mut self, id: &str, error: &anyhow::Error, at: DateTime<Utc>) -> bool {
+        let entry = self
+            .failed
+            .entry(id.to_string())
+            .or_insert_with(|| FailedMessage {
+                attempts: 0,
+                first_failed: at,
+                last_error: String::new(),
+            });
+        entry.attempts += 1;
+        entry.last_error = format!("{:#}", error);
+
+        if entry.attempts >= MAX_ATTEMPTS {
+            self.failed.remove(id);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_and_retries() {
+        let at = "2024-06-12T08:30:00Z".parse().unwrap();
+        let error = anyhow::anyhow!("rate limited");
+        let mut checkpoint = Checkpoint::default();
+
+        assert!(checkpoint.record_failure("m1", &error, at));
+        assert!(checkpoint.record_failure("m2", &error, at));
+        assert_eq!(checkpoint.failed["m1"].attempts, 1);
+        assert_eq!(checkpoint.failed["m1"].last_error, "rate limited");
+
+        // m2 is listed again anyway, so only m1 is added
+        assert_eq!(checkpoint.retries(&["m2".to_string()]), vec!["m1"]);
+
+        checkpoint.record_success("m2");
+        assert!(!checkpoint.failed.contains_key("m2"));
+
+        for _ in 1..MAX_ATTEMPTS - 1 {
+            assert!(checkpoint.record_failure("m1", &error, at));
+        }
+        assert!(!checkpoint.record_failure("m1", &error, at));
+        assert!(checkpoint.failed.is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use gmail_router::config::{Action, ConfigOverrides, CONFIG_DIR_ENV};
+use gmail_router::rules::Age;
+use std::path::PathBuf;
+
+/// Automatic email router for Gmail.
+///
+/// Every credentials.yaml field can also be set with a GMAIL_ROUTER_<FIELD> environment
+/// variable. Precedence: command-line flags, then environment, then the config file.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// Configuration directory [default: <user config dir>/gmail_router]
+    #[arg(long, global = true, env = CONFIG_DIR_ENV, value_name = "DIR")]
+    pub config_dir: Option<PathBuf>,
+
+    /// Output format of reporting commands
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Path to the Google OAuth2 client secret JSON
+    #[arg(long = "secret", global = true, value_name = "PATH")]
+    pub google_credentials_path: Option<String>,
+
+    /// Domain whose recipient addresses are routed
+    #[arg(long, global = true)]
+    pub domain: Option<String>,
+
+    /// Seconds between processing cycles
+    #[arg(long = "interval", global = true, value_name = "SECONDS")]
+    pub check_interval_seconds: Option<u64>,
+
+    /// Date of the oldest message to scan, e.g. 2024-01-01T00:00:00Z
+    #[arg(long, global = true, value_name = "DATE")]
+    pub start_date: Option<String>,
+
+    /// Override any config field, e.g. --set check_interval_seconds=60
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = ConfigOverrides::parse_assignment)]
+    pub set: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Scan new mail, then process it every check interval (default)
+    Run,
+    /// Scan new mail and run a single processing cycle
+    Once,
+    /// Rescan all mail since start_date and add every address found
+    Scan,
+    /// Inspect and edit the address list in routing.yaml
+    #[command(subcommand)]
+    Rules(RulesCommand),
+    /// Show how a message would be routed and why
+    Explain {
+        /// Gmail message ID
+        #[arg(required_unless_present = "eml", conflicts_with = "eml")]
+        message_id: Option<String>,
+        /// Explain a raw message from an .eml file instead
+        #[arg(long, value_name = "PATH")]
+        eml: Option<PathBuf>,
+    },
+    /// Route local .eml, .mbox or Maildir messages without touching the mailbox
+    TestRules(TestRulesArgs),
+    /// Show address list statistics
+    Stats,
+    /// Show possible leaks found by leak detection
+    Leaks {
+        /// Only show leaks of this local part
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Search the audit log of routing decisions
+    Audit(AuditArgs),
+    /// Restore the labels messages had before the router acted on them
+    Undo(UndoArgs),
+    /// Put backed-up messages back into the inbox
+    Restore {
+        /// A backed-up message file, an mbox file, or a folder of them
+        path: PathBuf,
+    },
+    /// List, release or expire quarantined messages
+    #[command(subcommand)]
+    Quarantine(QuarantineCommand),
+    /// Export blocked addresses as Gmail filters, or import Gmail filters as rules
+    #[command(subcommand)]
+    Filters(FiltersCommand),
+    /// Manage the Gmail authorization
+    #[command(subcommand)]
+    Auth(AuthCommand),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RulesCommand {
+    /// List known addresses
+    List,
+    /// Allow mail to the given local parts
+    Allow {
+        #[arg(required = true)]
+        addresses: Vec<String>,
+    },
+    /// Block mail to the given local parts
+    Block {
+        #[arg(required = true)]
+        addresses: Vec<String>,
+    },
+    /// Choose what happens to mail for a blocked local part
+    SetAction { address: String, action: Action },
+    /// Attach a note to a local part; omit the text to clear it
+    Note {
+        address: String,
+        text: Option<String>,
+    },
+    /// Replace the labels of a local part; omit them to clear
+    Label {
+        address: String,
+        labels: Vec<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct AuditArgs {
+    /// Only entries from this recent period, e.g. 1h or 2d
+    #[arg(long)]
+    pub since: Option<Age>,
+    /// Only mail to this local part
+    #[arg(long)]
+    pub address: Option<String>,
+    /// Only mail from senders matching this pattern, e.g. *@shop.com
+    #[arg(long)]
+    pub from: Option<String>,
+    /// Only this action; `keep` selects messages that were left alone
+    #[arg(long)]
+    pub action: Option<String>,
+    /// Only this message or thread ID
+    #[arg(long)]
+    pub message: Option<String>,
+    /// Only this run
+    #[arg(long)]
+    pub run: Option<String>,
+    /// Only actions that failed
+    #[arg(long)]
+    pub failed: bool,
+    /// Show at most this many of the newest entries
+    #[arg(long, default_value_t = 50)]
+    pub limit: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct TestRulesArgs {
+    /// Message files, mbox files, or folders of them
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+    /// Routing config to test [default: routing.yaml in the config dir]
+    #[arg(long, value_name = "PATH")]
+    pub routing: Option<PathBuf>,
+    /// YAML file of expected actions per message; fails if any differs
+    #[arg(long, value_name = "PATH")]
+    pub expect: Option<PathBuf>,
+    /// Write the current results to the --expect file instead of checking them
+    #[arg(long, requires = "expect")]
+    pub update: bool,
+    /// Measure message ages from this time instead of now, e.g. 2024-06-01T00:00:00Z
+    #[arg(long, value_name = "DATE")]
+    pub now: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Args)]
+#[command(group(
+    ArgGroup::new("selection")
+        .required(true)
+        .multiple(true)
+        .args(["run_id", "since", "address"])
+))]
+pub struct UndoArgs {
+    /// Only undo actions of this run
+    pub run_id: Option<String>,
+    /// Only undo actions from this recent period, e.g. 1h or 2d
+    #[arg(long)]
+    pub since: Option<Age>,
+    /// Only undo actions on mail to this local part
+    #[arg(long)]
+    pub address: Option<String>,
+    /// Show what would be restored without changing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QuarantineCommand {
+    /// List quarantined messages
+    List,
+    /// Move quarantined messages back to the inbox
+    Release {
+        /// Gmail message IDs
+        #[arg(required = true)]
+        message_ids: Vec<String>,
+    },
+    /// Expire messages past the retention period now
+    Purge,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FiltersCommand {
+    /// Create a Gmail filter for every blocked address that has none
+    Export {
+        /// Also delete exported filters that routing.yaml no longer asks for
+        #[arg(long)]
+        sync: bool,
+        /// Show what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Add the existing Gmail filters to routing.yaml as rules
+    Import {
+        /// Show the rules without adding them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuthCommand {
+    /// Authorize in the browser and cache the token
+    Login,
+    /// Check whether a cached token exists and still works
+    Status,
+    /// Revoke the cached token and delete it
+    Revoke,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Validate credentials.yaml and routing.yaml
+    Check,
+    /// Print the effective configuration
+    Show,
+}
+
+impl GlobalArgs {
+    pub fn overrides(&self) -> ConfigOverrides {
+        let mut overrides = ConfigOverrides::default();
+
+        if let Some(path) = &self.google_credentials_path {
+            overrides.set("google_credentials_path", path.clone());
+        }
+        if let Some(domain) = &self.domain {
+            overrides.set("domain", domain.clone());
+        }
+        if let Some(interval) = self.check_interval_seconds {
+            overrides.set("check_interval_seconds", interval.to_string());
+        }
+        if let Some(date) = &self.start_date {
+            overrides.set("start_date", date.clone());
+        }
+        for (key, value) in &self.set {
+            overrides.set(key.clone(), value.clone());
+        }
+
+        overrides
+    }
+}
@@ -0,0 +1,813 @@
+use crate::cli::{
+    AuditArgs, AuthCommand, ConfigCommand, FiltersCommand, GlobalArgs, OutputFormat,
+    QuarantineCommand, RulesCommand, TestRulesArgs, UndoArgs,
+};
+use anyhow::{Context, Result};
+use gmail_router::config::{
+    self, get_config_path, AddressEntry, RoutingConfig, AUDIT_FILE, CREDENTIALS_FILE, JOURNAL_FILE,
+    LEAK_REPORT_FILE, ROUTING_FILE, STATE_FILE, TOKEN_CACHE_FILE,
+};
+use gmail_router::state::{AddressStats, State};
+use gmail_router::{
+    audit, backup, filters, gmail, journal, leak, processor, quarantine, rule_test,
+};
+use serde_json::json;
+use std::path::Path;
+
+fn load_routing() -> Result<RoutingConfig> {
+    let path = get_config_path(ROUTING_FILE);
+    if !path.exists() {
+        anyhow::bail!(
+            "Routing config {:?} not found. Run `gmail_router scan` first",
+            path
+        );
+    }
+    RoutingConfig::load(&path).context("Failed to load routing config")
+}
+
+fn print_json(value: &serde_json::Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+pub fn rules(cmd: RulesCommand, format: OutputFormat) -> Result<()> {
+    let routing_config = load_routing()?;
+
+    let edit: Box<dyn FnOnce(&mut RoutingConfig)> = match cmd {
+        RulesCommand::List => {
+            let addresses = &routing_config.addresses;
+            let state = State::load(get_config_path(STATE_FILE))?;
+            let no_stats = AddressStats::default();
+
+            match format {
+                OutputFormat::Json => {
+                    let entries: Vec<_> = addresses
+                        .iter()
+                        .map(|(address, entry)| {
+                            let stats = state.stats(address).unwrap_or(&no_stats);
+                            json!({
+                                "address": address,
+                                "allowed": entry.allowed,
+                                "action": routing_config.action_for(address).to_string(),
+                                "first_seen": stats.first_seen,
+                                "last_seen": stats.last_seen,
+                                "messages": stats.messages,
+                                "sender_domains": stats.sender_domains,
+                                "note": entry.note,
+                                "labels": entry.labels,
+                                "protected": entry.protected,
+                                "priority": entry.priority,
+                            })
+                        })
+                        .collect();
+                    print_json(&json!(entries))?;
+                }
+                OutputFormat::Text => {
+                    for (index, rule) in routing_config.rules.iter().enumerate() {
+                        println!("  rule     {} -> {}", rule.label(index), rule.action);
+                    }
+                    for (address, entry) in addresses {
+                        if entry.allowed {
+                            println!("  allowed  {}", address);
+                        } else {
+                            println!(
+                                "  blocked  {} ({})",
+                                address,
+                                routing_config.action_for(address)
+                            );
+                        }
+                        print_entry_details(entry, state.stats(address).unwrap_or(&no_stats));
+                    }
+                }
+            }
+            return Ok(());
+        }
+        RulesCommand::Allow { addresses } => Box::new(move |c| {
+            for address in &addresses {
+                c.set_allowed(address, true);
+            }
+        }),
+        RulesCommand::Block { addresses } => Box::new(move |c| {
+            for address in &addresses {
+                c.set_allowed(address, false);
+            }
+        }),
+        RulesCommand::SetAction { address, action } => {
+            Box::new(move |c| c.set_action(&address, action))
+        }
+        RulesCommand::Note { address, text } => Box::new(move |c| c.set_note(&address, text)),
+        RulesCommand::Label { address, labels } => {
+            Box::new(move |c| c.set_labels(&address, labels.into_iter().collect()))
+        }
+    };
+
+    RoutingConfig::update(get_config_path(ROUTING_FILE), edit)
+        .context("Failed to save routing config")?;
+    println!("Routing config updated");
+
+    Ok(())
+}
+
+fn print_entry_details(entry: &AddressEntry, stats: &AddressStats) {
+    if entry.protected {
+        println!("           protected");
+    }
+    if entry.priority != 0 {
+        println!("           priority: {}", entry.priority);
+    }
+    if let Some(note) = &entry.note {
+        println!("           note: {}", note);
+    }
+    if !entry.labels.is_empty() {
+        let labels: Vec<_> = entry.labels.iter().map(String::as_str).collect();
+        println!("           labels: {}", labels.join(", "));
+    }
+    if let (Some(first), Some(last)) = (stats.first_seen, stats.last_seen) {
+        println!(
+            "           {} messages, {} .. {}",
+            stats.messages,
+            first.format("%Y-%m-%d"),
+            last.format("%Y-%m-%d")
+        );
+    }
+    if !stats.sender_domains.is_empty() {
+        let domains: Vec<_> = stats
+            .top_sender_domains()
+            .into_iter()
+            .take(3)
+            .map(|(domain, count)| format!("{} ({})", domain, count))
+            .collect();
+        println!("           from: {}", domains.join(", "));
+    }
+}
+
+pub async fn explain(
+    global: &GlobalArgs,
+    message_id: Option<&str>,
+    eml: Option<&Path>,
+) -> Result<()> {
+    let (domain, message, source) = match (message_id, eml) {
+        (_, Some(path)) => {
+            let creds_config = crate::load_credentials(global)?;
+            let raw = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+            let message = gmail_router::eml::parse(&raw)
+                .with_context(|| format!("Failed to parse {:?}", path))?;
+            (creds_config.domain, message, path.display().to_string())
+        }
+        (Some(message_id), None) => {
+            let (creds_config, gmail_client) = crate::connect(global).await?;
+            let message = gmail_client.get_message(message_id).await?;
+            (creds_config.domain, message, message_id.to_string())
+        }
+        (None, None) => anyhow::bail!("Give a message ID or --eml <PATH>"),
+    };
+    let routing_config = load_routing()?;
+
+    let sources = processor::recipient_sources(&message, &domain)?;
+    let facts = processor::MessageFacts::from_message(&message, &domain)?;
+    let outcomes = processor::evaluate_rules(&facts, &routing_config, chrono::Utc::now());
+    let decision = processor::route(&facts, &routing_config);
+    let action = decision.action;
+    let rule = decision
+        .rule
+        .map(|index| routing_config.rules[index].label(index));
+    let policy = serde_json::to_value(routing_config.conflict_policy)?;
+    let policy = policy.as_str().unwrap_or_default();
+
+    match global.format {
+        OutputFormat::Json => print_json(&json!({
+            "message": source,
+            "from": facts.from,
+            "reply_to": facts.reply_to,
+            "return_path": facts.return_path,
+            "subject": facts.subject,
+            "recipients": sources
+                .iter()
+                .map(|r| json!({
+                    "address": r.local_part,
+                    "header": r.header,
+                    "routed": r.is_routed(),
+                    "allowed": routing_config.is_allowed(&r.local_part),
+                    "protected": routing_config.is_protected(&r.local_part),
+                    "priority": routing_config.priority(&r.local_part),
+                }))
+                .collect::<Vec<_>>(),
+            "conflict_policy": policy,
+            "rules": routing_config
+                .rules
+                .iter()
+                .zip(&outcomes)
+                .enumerate()
+                .map(|(index, (rule, outcome))| {
+                    let (result, failed) = match outcome {
+                        processor::RuleOutcome::Matched => ("matched", None),
+                        processor::RuleOutcome::Failed(fields) => ("failed", Some(fields)),
+                        processor::RuleOutcome::NotReached => ("not_reached", None),
+                    };
+                    json!({
+                        "rule": rule.label(index),
+                        "action": rule.action.to_string(),
+                        "result": result,
+                        "failed": failed,
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "rule": rule,
+            "protected": decision.protected,
+            "action": action.map(|a| a.to_string()),
+        }))?,
+        OutputFormat::Text => {
+            println!("Message {}", source);
+            for (header, value) in [
+                ("From", &facts.from),
+                ("Reply-To", &facts.reply_to),
+                ("Return-Path", &facts.return_path),
+                ("Subject", &facts.subject),
+            ] {
+                if let Some(value) = value {
+                    println!("  {}: {}", header, value);
+                }
+            }
+
+            println!("Recipients:");
+            if sources.is_empty() {
+                println!("  None on {}", domain);
+            }
+            for recipient in &sources {
+                let address = &recipient.local_part;
+                let mut notes = vec![if routing_config.is_allowed(address) {
+                    "allowed".to_string()
+                } else {
+                    "blocked".to_string()
+                }];
+                if routing_config.is_protected(address) {
+                    notes.push("protected".to_string());
+                }
+                if routing_config.priority(address) != 0 {
+                    notes.push(format!("priority {}", routing_config.priority(address)));
+                }
+                if !recipient.is_routed() {
+                    notes.push("not used for routing".to_string());
+                }
+                println!(
+                    "  {}@{} ({}): {}",
+                    address,
+                    domain,
+                    recipient.header,
+                    notes.join(", ")
+                );
+            }
+            println!("Conflict policy: {}", policy);
+
+            if !routing_config.rules.is_empty() {
+                println!("Rules:");
+            }
+            for (index, (rule, outcome)) in routing_config.rules.iter().zip(&outcomes).enumerate() {
+                let result = match outcome {
+                    processor::RuleOutcome::Matched => "matched".to_string(),
+                    processor::RuleOutcome::Failed(fields) => {
+                        format!("no match on {}", fields.join(", "))
+                    }
+                    processor::RuleOutcome::NotReached => "not reached".to_string(),
+                };
+                println!("  {} ({}): {}", rule.label(index), rule.action, result);
+            }
+
+            match &rule {
+                _ if decision.protected => {
+                    println!("Decided by a protected recipient; rules are not applied")
+                }
+                Some(rule) => println!("Decided by {}", rule),
+                None => println!("Decided by the address list"),
+            }
+            match action {
+                Some(action) => println!("Action: {}", action),
+                None => println!("Action: keep"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn test_rules(global: &GlobalArgs, args: TestRulesArgs) -> Result<()> {
+    let domain = match &global.domain {
+        Some(domain) => domain.clone(),
+        None => crate::load_credentials(global)?.domain,
+    };
+    let routing_config = match &args.routing {
+        Some(path) => RoutingConfig::load(path)
+            .with_context(|| format!("Failed to load routing config {:?}", path))?,
+        None => load_routing()?,
+    };
+    let now = args.now.unwrap_or_else(chrono::Utc::now);
+
+    let mut cases = rule_test::run(&args.paths, &domain, &routing_config, now)?;
+
+    let mut missing = Vec::new();
+    if let Some(path) = &args.expect {
+        if args.update {
+            rule_test::save_expectations(path, &cases)?;
+            println!("Wrote {} expected actions to {:?}", cases.len(), path);
+            return Ok(());
+        }
+        missing = rule_test::apply_expectations(&mut cases, rule_test::load_expectations(path)?);
+    }
+
+    match global.format {
+        OutputFormat::Json => print_json(&json!({
+            "messages": cases,
+            "missing": missing,
+        }))?,
+        OutputFormat::Text => {
+            for case in &cases {
+                let status = match &case.expected {
+                    None => "",
+                    Some(_) if case.passed() => "ok   ",
+                    Some(_) => "FAIL ",
+                };
+                print!("{}{}: {}", status, case.name, case.action);
+                if let Some(rule) = &case.rule {
+                    print!(" ({})", rule);
+                }
+                match &case.expected {
+                    Some(expected) if !case.passed() => println!(", expected {}", expected),
+                    _ => println!(),
+                }
+            }
+            for name in &missing {
+                println!("FAIL {}: expected but not found", name);
+            }
+        }
+    }
+
+    let failed = cases.iter().filter(|case| !case.passed()).count();
+    if failed > 0 || !missing.is_empty() {
+        anyhow::bail!(
+            "{} of {} messages did not get the expected action, {} expected messages missing",
+            failed,
+            cases.len(),
+            missing.len()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn stats(format: OutputFormat) -> Result<()> {
+    let routing_config = load_routing()?;
+    let state = State::load(get_config_path(STATE_FILE))?;
+
+    let allowed = routing_config
+        .addresses
+        .values()
+        .filter(|entry| entry.allowed)
+        .count();
+    let blocked: Vec<_> = routing_config
+        .addresses
+        .iter()
+        .filter(|(_, entry)| !entry.allowed)
+        .map(|(address, _)| address)
+        .collect();
+
+    let mut by_action = std::collections::BTreeMap::new();
+    for address in &blocked {
+        *by_action
+            .entry(routing_config.action_for(address).to_string())
+            .or_insert(0) += 1;
+    }
+
+    match format {
+        OutputFormat::Json => print_json(&json!({
+            "addresses": routing_config.addresses.len(),
+            "allowed": allowed,
+            "blocked": blocked.len(),
+            "blocked_by_action": by_action,
+            "last_scan": state.scanned_until,
+            "last_run": state.last_run,
+            "totals": state.counters,
+        }))?,
+        OutputFormat::Text => {
+            println!("Addresses: {}", routing_config.addresses.len());
+            println!("  Allowed: {}", allowed);
+            println!("  Blocked: {}", blocked.len());
+            for (action, count) in &by_action {
+                println!("    {}: {}", action, count);
+            }
+            if let Some(scanned) = state.scanned_until {
+                println!("Last scan: {}", scanned);
+            }
+            if let Some(run) = &state.last_run {
+                match &run.error {
+                    Some(error) => println!("Last run: {} failed: {}", run.started, error),
+                    None => println!(
+                        "Last run: {}, {} processed, {} routed, {} failed",
+                        run.started, run.processed, run.routed, run.failed
+                    ),
+                }
+            }
+            let totals = &state.counters;
+            if totals.runs > 0 {
+                println!(
+                    "Totals: {} runs ({} failed), {} processed, {} routed",
+                    totals.runs, totals.failed_runs, totals.processed, totals.routed
+                );
+                for (action, count) in &totals.actions {
+                    println!("    {}: {}", action, count);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn leaks(format: OutputFormat, address: Option<&str>) -> Result<()> {
+    let events: Vec<_> = leak::load_report(get_config_path(LEAK_REPORT_FILE))?
+        .into_iter()
+        .filter(|event| address.is_none_or(|a| event.address == a.to_lowercase()))
+        .collect();
+
+    match format {
+        OutputFormat::Json => print_json(&serde_json::to_value(&events)?)?,
+        OutputFormat::Text => {
+            if events.is_empty() {
+                println!("No possible leaks found");
+            }
+            for event in &events {
+                println!(
+                    "{}  {} <- {} (usually {}), message {}",
+                    event.detected_at.format("%Y-%m-%d %H:%M"),
+                    event.address,
+                    event.sender_domain,
+                    event.known_domains.join(", "),
+                    event.message_id
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn audit(format: OutputFormat, args: AuditArgs) -> Result<()> {
+    let query = audit::AuditQuery {
+        since: args.since.map(|age| chrono::Utc::now() - age.0),
+        address: args.address,
+        from: args.from,
+        action: args.action,
+        message_id: args.message,
+        run_id: args.run,
+        failed: args.failed,
+    };
+    let entries = audit::load(get_config_path(AUDIT_FILE))?;
+    let matching: Vec<_> = entries.iter().filter(|e| query.matches(e)).collect();
+    let shown = &matching[matching.len().saturating_sub(args.limit)..];
+
+    match format {
+        OutputFormat::Json => print_json(&json!(shown))?,
+        OutputFormat::Text => {
+            for entry in shown {
+                let action = match (entry.action, entry.undo) {
+                    (None, true) => "undo label".to_string(),
+                    (None, false) => "keep".to_string(),
+                    (Some(action), false) => action.to_string(),
+                    (Some(action), true) => format!("undo {}", action),
+                };
+                println!(
+                    "{}  {:<12} {:<10} {}  {}  to: {}",
+                    entry.at.format("%Y-%m-%d %H:%M:%S"),
+                    action,
+                    format!("{:?}", entry.result).to_lowercase(),
+                    entry.message_id,
+                    entry.from.as_deref().unwrap_or("-"),
+                    entry.recipients.join(", ")
+                );
+                if let Some(subject) = &entry.subject {
+                    println!("    {}", subject);
+                }
+                if let Some(rule) = &entry.rule {
+                    println!("    rule: {}", rule);
+                }
+                if !entry.labels.is_empty() {
+                    println!("    labels: {}", entry.labels.join(", "));
+                }
+                if let Some(error) = &entry.error {
+                    println!("    error: {}", error);
+                }
+            }
+            if matching.len() > shown.len() {
+                println!(
+                    "({} older entries not shown, use --limit)",
+                    matching.len() - shown.len()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn undo(global: &GlobalArgs, args: UndoArgs) -> Result<()> {
+    let journal_path = get_config_path(JOURNAL_FILE);
+    let entries = journal::load(&journal_path)?;
+    let filter = journal::UndoFilter {
+        since: args.since.map(|age| chrono::Utc::now() - age.0),
+        address: args.address,
+        run_id: args.run_id,
+    };
+    let selected = journal::select(&entries, &filter);
+
+    if selected.is_empty() {
+        println!("Nothing to undo");
+        return Ok(());
+    }
+
+    if args.dry_run {
+        match global.format {
+            OutputFormat::Json => print_json(&json!(selected))?,
+            OutputFormat::Text => {
+                for entry in &selected {
+                    println!(
+                        "{}  {}  {}  {}  to: {}",
+                        entry.at.format("%Y-%m-%d %H:%M"),
+                        entry.run_id,
+                        entry.message_id,
+                        entry.change(),
+                        entry.recipients.join(", ")
+                    );
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let (creds_config, gmail_client) = crate::connect(global).await?;
+    let recorder = crate::recorder(&creds_config)
+        .with_run_id(format!("undo-{}", journal::new_run_id(chrono::Utc::now())));
+    let restored = journal::undo(&gmail_client, &recorder, &selected).await?;
+    println!("Restored {} of {} messages", restored, selected.len());
+
+    Ok(())
+}
+
+pub async fn restore(global: &GlobalArgs, path: &Path) -> Result<()> {
+    let messages = backup::read_messages(path)?;
+    if messages.is_empty() {
+        println!("No messages found in {:?}", path);
+        return Ok(());
+    }
+
+    let (_, gmail_client) = crate::connect(global).await?;
+    for raw in messages {
+        let id = gmail_client
+            .insert_message(raw, vec!["INBOX".to_string()])
+            .await?;
+        println!("Restored message {}", id);
+    }
+
+    Ok(())
+}
+
+pub async fn quarantine(global: &GlobalArgs, cmd: QuarantineCommand) -> Result<()> {
+    let (creds_config, gmail_client) = crate::connect(global).await?;
+
+    match cmd {
+        QuarantineCommand::List => {
+            let mut entries = Vec::new();
+            for message_id in quarantine::list(&gmail_client).await? {
+                let message = gmail_client.get_message(&message_id).await?;
+                let facts = processor::MessageFacts::from_message(&message, &creds_config.domain)?;
+                entries.push((message_id, facts));
+            }
+
+            match global.format {
+                OutputFormat::Json => print_json(&json!(entries
+                    .iter()
+                    .map(|(id, facts)| json!({
+                        "message_id": id,
+                        "received": facts.received,
+                        "from": facts.from,
+                        "subject": facts.subject,
+                        "recipients": facts.recipients,
+                    }))
+                    .collect::<Vec<_>>()))?,
+                OutputFormat::Text => {
+                    if entries.is_empty() {
+                        println!("No quarantined messages");
+                    }
+                    for (id, facts) in &entries {
+                        println!(
+                            "{}  {}  {}  to: {}",
+                            id,
+                            facts.received.map_or("unknown".to_string(), |d| d
+                                .format("%Y-%m-%d")
+                                .to_string()),
+                            facts.from.as_deref().unwrap_or("unknown"),
+                            facts.recipients.join(", ")
+                        );
+                        if let Some(subject) = &facts.subject {
+                            println!("    {}", subject);
+                        }
+                    }
+                }
+            }
+        }
+        QuarantineCommand::Release { message_ids } => {
+            let recorder = crate::recorder(&creds_config);
+            for message_id in &message_ids {
+                quarantine::release(&gmail_client, &creds_config.domain, &recorder, message_id)
+                    .await?;
+                println!("Released {}", message_id);
+            }
+        }
+        QuarantineCommand::Purge => {
+            let purged = quarantine::purge_expired(
+                &gmail_client,
+                &creds_config.quarantine,
+                &creds_config.domain,
+                &crate::recorder(&creds_config),
+                &get_config_path(STATE_FILE),
+            )
+            .await?;
+            println!("Expired {} messages", purged);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn filters(global: &GlobalArgs, cmd: FiltersCommand) -> Result<()> {
+    let (creds_config, gmail_client) = crate::connect(global).await?;
+    let routing_path = get_config_path(ROUTING_FILE);
+    let state_path = get_config_path(STATE_FILE);
+
+    match cmd {
+        FiltersCommand::Export { sync, dry_run } => {
+            let report = filters::export(
+                &gmail_client,
+                &load_routing()?,
+                &creds_config.domain,
+                &state_path,
+                sync,
+                dry_run,
+            )
+            .await?;
+
+            match global.format {
+                OutputFormat::Json => print_json(&json!(report))?,
+                OutputFormat::Text => {
+                    for warning in &report.warnings {
+                        println!("Warning: {}", warning);
+                    }
+                    for address in &report.created {
+                        println!("Created filter for {}", address);
+                    }
+                    for address in &report.deleted {
+                        println!("Deleted filter for {}", address);
+                    }
+                    println!(
+                        "{} created, {} deleted, {} unchanged{}",
+                        report.created.len(),
+                        report.deleted.len(),
+                        report.unchanged,
+                        if dry_run { " (dry run)" } else { "" }
+                    );
+                }
+            }
+        }
+        FiltersCommand::Import { dry_run } => {
+            let imported = filters::import(
+                &gmail_client,
+                &load_routing()?,
+                &creds_config.domain,
+                &state_path,
+            )
+            .await?;
+            let rules: Vec<_> = imported
+                .iter()
+                .filter_map(|filter| filter.rule.as_ref().ok().cloned())
+                .collect();
+
+            match global.format {
+                OutputFormat::Json => print_json(&json!(imported
+                    .iter()
+                    .map(|filter| match &filter.rule {
+                        Ok(rule) => json!({ "filter_id": filter.id, "rule": rule }),
+                        Err(reason) => json!({ "filter_id": filter.id, "skipped": reason }),
+                    })
+                    .collect::<Vec<_>>()))?,
+                OutputFormat::Text => {
+                    for filter in &imported {
+                        match &filter.rule {
+                            Ok(rule) => {
+                                println!("Imported filter {} as {} rule", filter.id, rule.action)
+                            }
+                            Err(reason) => println!("Skipped filter {}: {}", filter.id, reason),
+                        }
+                    }
+                    if imported.is_empty() {
+                        println!("No new Gmail filters to import");
+                    }
+                }
+            }
+
+            if !dry_run && !rules.is_empty() {
+                RoutingConfig::update(&routing_path, |config| config.rules.extend(rules))
+                    .context("Failed to update routing config")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn auth(global: &GlobalArgs, cmd: AuthCommand) -> Result<()> {
+    match cmd {
+        AuthCommand::Login => {
+            let creds_config = crate::load_credentials(global)?;
+            gmail::GmailClient::login(&creds_config.google_credentials_path).await?;
+            println!("Auth success!");
+        }
+        AuthCommand::Status => {
+            let cached = get_config_path(TOKEN_CACHE_FILE).exists();
+            // Without a cached token, creating the client would start the browser flow.
+            let result = if cached {
+                let creds_config = crate::load_credentials(global)?;
+                gmail::GmailClient::new(&creds_config.google_credentials_path)
+                    .await
+                    .map(|_| ())
+            } else {
+                Err(anyhow::anyhow!("not logged in"))
+            };
+
+            match global.format {
+                OutputFormat::Json => print_json(&json!({
+                    "token_cached": cached,
+                    "authorized": result.is_ok(),
+                    "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+                }))?,
+                OutputFormat::Text => match &result {
+                    Ok(()) => println!("Authorized"),
+                    Err(e) => println!("Not authorized: {:#}", e),
+                },
+            }
+        }
+        AuthCommand::Revoke => {
+            let count = gmail::revoke_cached_tokens().await?;
+            println!("Revoked {} token(s) and deleted the token cache", count);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn config(global: &GlobalArgs, cmd: ConfigCommand) -> Result<()> {
+    match cmd {
+        ConfigCommand::Show => {
+            let creds_config = crate::load_credentials(global)?;
+            match global.format {
+                OutputFormat::Json => print_json(&serde_json::to_value(&creds_config)?)?,
+                OutputFormat::Text => {
+                    println!("# config dir: {}", config::config_dir().display());
+                    print!("{}", creds_config.to_yaml()?);
+                }
+            }
+        }
+        ConfigCommand::Check => {
+            let creds = crate::load_credentials(global);
+            let routing = RoutingConfig::load(get_config_path(ROUTING_FILE));
+
+            match global.format {
+                OutputFormat::Json => print_json(&json!({
+                    "credentials": creds.as_ref().err().map(|e| format!("{:#}", e)),
+                    "routing": routing.as_ref().err().map(|e| format!("{:#}", e)),
+                }))?,
+                OutputFormat::Text => {
+                    match &creds {
+                        Ok(config) => {
+                            println!("{}: ok", CREDENTIALS_FILE);
+                            println!("  Domain: {}", config.domain);
+                            println!("  Check interval: {} s", config.check_interval_seconds);
+                            println!("  Start date: {}", config.start_date);
+                        }
+                        Err(e) => println!("{}: {:#}", CREDENTIALS_FILE, e),
+                    }
+                    match &routing {
+                        Ok(config) => {
+                            println!("{}: ok", ROUTING_FILE);
+                            println!("  Addresses count: {}", config.addresses.len());
+                        }
+                        Err(e) => println!("{}: {:#}", ROUTING_FILE, e),
+                    }
+                }
+            }
+
+            if creds.is_err() || routing.is_err() {
+                anyhow::bail!("Configuration check failed");
+            }
+        }
+    }
+
+    Ok(())
+}
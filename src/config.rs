@@ -1,14 +1,45 @@
+use crate::audit::AuditConfig;
+use crate::backup::BackupConfig;
+use crate::filters::FiltersConfig;
+use crate::gmail::SearchConfig;
+use crate::labels::AliasLabelsConfig;
+use crate::leak::LeakDetectionConfig;
+use crate::metrics::MetricsConfig;
+use crate::notify::NotifyConfig;
+use crate::quarantine::QuarantineConfig;
+use crate::rules::Rule;
+use crate::yaml_edit::YamlDocument;
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use fs2::FileExt;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::warn;
+
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides the configuration directory for the rest of the process.
+/// Must be called before the first `get_config_path`.
+pub fn set_config_dir(path: PathBuf) {
+    let _ = CONFIG_DIR.set(path);
+}
+
+pub fn config_dir() -> PathBuf {
+    CONFIG_DIR.get().cloned().unwrap_or_else(|| {
+        let mut path = dirs::config_dir().expect("Cannot find config dir");
+        path.push("gmail_router");
+        path
+    })
+}
 
 pub fn get_config_path(filename: &str) -> PathBuf {
-    let mut path = dirs::config_dir().expect("Cannot find config dir");
-    path.push("gmail_router");
+    let mut path = config_dir();
     std::fs::create_dir_all(&path).expect("Cannot create config dir");
     path.push(filename);
     path
@@ -16,19 +47,400 @@ pub fn get_config_path(filename: &str) -> PathBuf {
 
 pub const CREDENTIALS_FILE: &str = "credentials.yaml";
 pub const ROUTING_FILE: &str = "routing.yaml";
+pub const TOKEN_CACHE_FILE: &str = "token_cache.json";
+pub const LEAK_REPORT_FILE: &str = "leaks.jsonl";
+pub const JOURNAL_FILE: &str = "journal.jsonl";
+pub const AUDIT_FILE: &str = "audit.jsonl";
+pub const STATE_FILE: &str = "state.json";
+/// Processing checkpoint of older versions, now part of [`STATE_FILE`]
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Schema version of credentials.yaml and routing.yaml written by this build.
+/// Files without a `version` key are version 0.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Shortest allowed `check_interval_seconds`.
+pub const MIN_CHECK_INTERVAL_SECONDS: u64 = 10;
+
+pub const ENV_PREFIX: &str = "GMAIL_ROUTER_";
+pub const CONFIG_DIR_ENV: &str = "GMAIL_ROUTER_CONFIG_DIR";
+
+/// Top-level fields of `CredentialsConfig` that environment variables may set.
+const ENV_KEYS: &[&str] = &[
+    "version",
+    "google_credentials_path",
+    "domain",
+    "check_interval_seconds",
+    "start_date",
+    "search",
+    "thread_mode",
+    "notify",
+    "leak_detection",
+    "quarantine",
+    "audit",
+    "backup",
+    "filters",
+    "alias_labels",
+    "metrics",
+];
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`,
+/// so readers never see a partially written file.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &str) -> Result<()> {
+    let path = path.as_ref();
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Invalid config file path")?;
+    let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let mut file = fs::File::create(&tmp_path).context("Failed to create temporary file")?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .context("Failed to write temporary file")?;
+
+    fs::rename(&tmp_path, path).context("Failed to replace config file")?;
+    Ok(())
+}
+
+/// Exclusive advisory lock on `<path>.lock`, held until dropped.
+///
+/// Taken around every read-modify-write of a config file so the daemon and
+/// CLI commands don't overwrite each other's changes.
+pub struct ConfigLock {
+    file: fs::File,
+}
+
+impl ConfigLock {
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut lock_path = path.as_ref().as_os_str().to_owned();
+        lock_path.push(".lock");
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .context("Failed to open lock file")?;
+        file.lock_exclusive()
+            .with_context(|| format!("Failed to lock {:?}", lock_path))?;
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// A set of `key = value` assignments applied on top of the credentials config file.
+///
+/// Keys are field names of `CredentialsConfig`; nested fields are separated by dots.
+/// Values are parsed as YAML scalars, so `3600` becomes a number and `true` a bool.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigOverrides {
+    values: Vec<(String, String)>,
+}
+
+impl ConfigOverrides {
+    pub fn from_env() -> Self {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Maps `GMAIL_ROUTER_CHECK_INTERVAL_SECONDS` to `check_interval_seconds`.
+    /// A double underscore selects a nested field: `GMAIL_ROUTER_A__B` is `a.b`.
+    /// Variables that name no known field are ignored with a warning.
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> Self {
+        let mut overrides = Self::default();
+        let mut vars: Vec<_> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != CONFIG_DIR_ENV)
+            .collect();
+        vars.sort();
+
+        for (name, value) in vars {
+            let key = name[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+            let field = key.split('.').next().unwrap_or_default();
+            if !ENV_KEYS.contains(&field) {
+                warn!("Ignoring {}: no such configuration field", name);
+                continue;
+            }
+            overrides.set(key, value);
+        }
+
+        overrides
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.values.push((key.into(), value.into()));
+    }
+
+    /// Parses a `key=value` assignment as given to `--set`.
+    pub fn parse_assignment(assignment: &str) -> Result<(String, String)> {
+        let (key, value) = assignment
+            .split_once('=')
+            .with_context(|| format!("Expected KEY=VALUE, got {:?}", assignment))?;
+        Ok((key.trim().to_string(), value.to_string()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn apply(&self, doc: &mut serde_yaml::Value) -> Result<()> {
+        for (key, raw) in &self.values {
+            let mut node = &mut *doc;
+            for segment in key.split('.') {
+                if !node.is_mapping() {
+                    *node = serde_yaml::Value::Mapping(Default::default());
+                }
+                let map = node.as_mapping_mut().expect("node is a mapping");
+                node = map
+                    .entry(serde_yaml::Value::String(segment.to_string()))
+                    .or_insert(serde_yaml::Value::Null);
+            }
+            *node = parse_scalar(raw);
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_scalar(raw: &str) -> serde_yaml::Value {
+    match serde_yaml::from_str::<serde_yaml::Value>(raw) {
+        Ok(value @ (serde_yaml::Value::Bool(_) | serde_yaml::Value::Number(_))) => value,
+        _ => serde_yaml::Value::String(raw.to_string()),
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct CredentialsConfig {
+    #[serde(default)]
+    pub version: u32,
     pub google_credentials_path: String,
     pub domain: String,
     pub check_interval_seconds: u64,
     pub start_date: DateTime<Utc>,
+    /// Which mail is routed; the inbox by default
+    #[serde(default)]
+    pub search: SearchConfig,
+    /// Route whole threads instead of single messages
+    #[serde(default)]
+    pub thread_mode: bool,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub leak_detection: LeakDetectionConfig,
+    #[serde(default)]
+    pub quarantine: QuarantineConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub filters: FiltersConfig,
+    #[serde(default)]
+    pub alias_labels: AliasLabelsConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+/// What happens to a message sent to a blocked address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Permanently delete the message
+    #[default]
+    Delete,
+    /// Move the message to the trash
+    Trash,
+    /// Move the message to spam
+    Spam,
+    /// Move the message to the quarantine label until it expires or is released
+    Quarantine,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Action::Delete => "delete",
+            Action::Trash => "trash",
+            Action::Spam => "spam",
+            Action::Quarantine => "quarantine",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "delete" => Ok(Action::Delete),
+            "trash" => Ok(Action::Trash),
+            "spam" => Ok(Action::Spam),
+            "quarantine" => Ok(Action::Quarantine),
+            _ => anyhow::bail!(
+                "Unknown action {:?}, expected delete, trash, spam or quarantine",
+                s
+            ),
+        }
+    }
+}
+
+/// Decides the fate of a message addressed to several of our addresses when
+/// some are blocked and some are not. Protected addresses win under every policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Route the message if any recipient is blocked
+    #[default]
+    AnyBlocked,
+    /// Route the message only if every recipient is blocked
+    AllBlocked,
+    /// The recipients with the highest `priority` decide; allowed wins a tie
+    Priority,
+}
+
+impl ConflictPolicy {
+    fn is_default(&self) -> bool {
+        *self == ConflictPolicy::AnyBlocked
+    }
+}
+
+/// A known local part. Written as a plain `true`/`false` until it carries
+/// a note or other settings.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(from = "AddressEntryRepr", into = "AddressEntryRepr")]
+pub struct AddressEntry {
+    pub allowed: bool,
+    /// Free-form note, e.g. "used for Amazon"
+    pub note: Option<String>,
+    pub labels: BTreeSet<String>,
+    /// Mail to a protected address is always kept, whatever the other recipients
+    pub protected: bool,
+    /// Used by [`ConflictPolicy::Priority`]; higher wins
+    pub priority: i32,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum AddressEntryRepr {
+    Allowed(bool),
+    Full(AddressEntryFields),
+}
+
+// Written by hand instead of `untagged` so that a typo in a field name is
+// reported as such rather than as "did not match any variant".
+impl<'de> Deserialize<'de> for AddressEntryRepr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ReprVisitor;
+
+        impl<'de> Visitor<'de> for ReprVisitor {
+            type Value = AddressEntryRepr;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("true, false or a mapping of address settings")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, allowed: bool) -> Result<Self::Value, E> {
+                Ok(AddressEntryRepr::Allowed(allowed))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let deserializer = serde::de::value::MapAccessDeserializer::new(map);
+                AddressEntryFields::deserialize(deserializer).map(AddressEntryRepr::Full)
+            }
+        }
+
+        deserializer.deserialize_any(ReprVisitor)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AddressEntryFields {
+    #[serde(default = "default_allowed")]
+    allowed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    labels: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    protected: bool,
+    #[serde(default, skip_serializing_if = "is_zero_i32")]
+    priority: i32,
+}
+
+fn default_allowed() -> bool {
+    true
+}
+
+fn is_zero_i32(n: &i32) -> bool {
+    *n == 0
+}
+
+impl From<AddressEntryRepr> for AddressEntry {
+    fn from(repr: AddressEntryRepr) -> Self {
+        match repr {
+            AddressEntryRepr::Allowed(allowed) => allowed.into(),
+            AddressEntryRepr::Full(fields) => Self {
+                allowed: fields.allowed,
+                note: fields.note,
+                labels: fields.labels,
+                protected: fields.protected,
+                priority: fields.priority,
+            },
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+impl From<AddressEntry> for AddressEntryRepr {
+    fn from(entry: AddressEntry) -> Self {
+        if entry == AddressEntry::from(entry.allowed) {
+            return AddressEntryRepr::Allowed(entry.allowed);
+        }
+        AddressEntryRepr::Full(AddressEntryFields {
+            allowed: entry.allowed,
+            note: entry.note,
+            labels: entry.labels,
+            protected: entry.protected,
+            priority: entry.priority,
+        })
+    }
+}
+
+impl From<bool> for AddressEntry {
+    fn from(allowed: bool) -> Self {
+        Self {
+            allowed,
+            note: None,
+            labels: BTreeSet::new(),
+            protected: false,
+            priority: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct RoutingConfig {
-    pub addresses: HashMap<String, bool>,
-    pub updated_date: DateTime<Utc>,
+    #[serde(default)]
+    pub version: u32,
+    pub addresses: BTreeMap<String, AddressEntry>,
+    /// Action for blocked addresses; `delete` when not listed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub actions: BTreeMap<String, Action>,
+    /// Rules checked in order before the address table
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    /// How to route a message whose recipients are partly allowed and partly blocked
+    #[serde(default, skip_serializing_if = "ConflictPolicy::is_default")]
+    pub conflict_policy: ConflictPolicy,
 }
 
 impl CredentialsConfig {
@@ -38,9 +450,85 @@ impl CredentialsConfig {
 
         let config: CredentialsConfig =
             serde_yaml::from_str(&contents).context("Failed to parse credentials config YAML")?;
+        config.validate()?;
 
         Ok(config)
     }
+
+    /// Loads the config file and applies `overrides` in order, so later sets win.
+    /// The file may be missing if the overrides provide every required field.
+    pub fn load_with_overrides<P: AsRef<Path>>(
+        path: P,
+        overrides: &[&ConfigOverrides],
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = if path.exists() {
+            Some(fs::read_to_string(path).context("Failed to read credentials config file")?)
+        } else if overrides.iter().any(|o| !o.is_empty()) {
+            None
+        } else {
+            anyhow::bail!("Credentials config file {:?} not found", path);
+        };
+
+        let mut doc = match &contents {
+            Some(contents) => {
+                serde_yaml::from_str(contents).context("Failed to parse credentials config YAML")?
+            }
+            None => serde_yaml::Value::Mapping(Default::default()),
+        };
+        for set in overrides {
+            set.apply(&mut doc)?;
+        }
+
+        let config: CredentialsConfig = match serde_yaml::from_value(doc) {
+            Ok(config) => config,
+            Err(e) => {
+                // Errors from the merged document carry no position; when the file
+                // has the same problem, report it with its line and column instead.
+                let located = contents
+                    .as_deref()
+                    .and_then(|c| serde_yaml::from_str::<CredentialsConfig>(c).err())
+                    .filter(|file_error| file_error.to_string().contains(&e.to_string()));
+                return Err(located.unwrap_or(e)).context("Invalid credentials config");
+            }
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        check_version(self.version).context("Invalid credentials config")?;
+        validate_domain(&self.domain).context("Invalid credentials config")?;
+
+        if self.check_interval_seconds < MIN_CHECK_INTERVAL_SECONDS {
+            anyhow::bail!(
+                "Invalid credentials config: check_interval_seconds must be at least {}, got {}",
+                MIN_CHECK_INTERVAL_SECONDS,
+                self.check_interval_seconds
+            );
+        }
+        if self.start_date > Utc::now() {
+            anyhow::bail!(
+                "Invalid credentials config: start_date {} is in the future",
+                self.start_date
+            );
+        }
+        if self.quarantine.retention_days == 0 {
+            anyhow::bail!(
+                "Invalid credentials config: quarantine.retention_days must be at least 1"
+            );
+        }
+        self.alias_labels
+            .validate()
+            .context("Invalid credentials config")?;
+
+        Ok(())
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).context("Failed to serialize credentials config to YAML")
+    }
 }
 
 impl RoutingConfig {
@@ -50,6 +538,7 @@ impl RoutingConfig {
 
         let config: RoutingConfig =
             serde_yaml::from_str(&contents).context("Failed to parse routing config YAML")?;
+        config.validate()?;
 
         Ok(config)
     }
@@ -58,27 +547,235 @@ impl RoutingConfig {
         let yaml =
             serde_yaml::to_string(&self).context("Failed to serialize routing config to YAML")?;
 
-        fs::write(path.as_ref(), yaml).context("Failed to write routing config file")?;
+        write_atomic(path.as_ref(), &yaml).context("Failed to write routing config file")?;
 
         Ok(())
     }
 
+    /// Locks the file, applies `f` to its current contents and saves the result.
+    /// A missing file starts from the default config.
+    ///
+    /// Only the changed entries are rewritten, so comments and the order of existing
+    /// entries survive; new entries are appended in sorted order.
+    pub fn update<P, F>(path: P, f: F) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut RoutingConfig),
+    {
+        let path = path.as_ref();
+        let _lock = ConfigLock::acquire(path)?;
+
+        if !path.exists() {
+            let mut config = Self {
+                version: CONFIG_VERSION,
+                ..Default::default()
+            };
+            f(&mut config);
+            config.validate()?;
+            config.save(path)?;
+            return Ok(config);
+        }
+
+        let contents = fs::read_to_string(path).context("Failed to read routing config file")?;
+        let previous: RoutingConfig =
+            serde_yaml::from_str(&contents).context("Failed to parse routing config YAML")?;
+        previous.validate()?;
+
+        let mut config = previous.clone();
+        f(&mut config);
+        config.validate()?;
+
+        if config == previous {
+            return Ok(config);
+        }
+
+        match config.edit_in_place(&previous, &contents) {
+            Ok(yaml) => write_atomic(path, &yaml).context("Failed to write routing config file")?,
+            Err(e) => {
+                warn!(
+                    "Rewriting routing config without preserving its layout: {:#}",
+                    e
+                );
+                config.save(path)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Applies the difference between `previous` and `self` to `contents`, the text
+    /// `previous` was parsed from.
+    fn edit_in_place(&self, previous: &RoutingConfig, contents: &str) -> Result<String> {
+        let mut doc = YamlDocument::parse(contents);
+
+        sync_section(&mut doc, "addresses", &previous.addresses, &self.addresses)?;
+        sync_section(&mut doc, "actions", &previous.actions, &self.actions)?;
+        if let Some(added) = self.rules.strip_prefix(previous.rules.as_slice()) {
+            if !added.is_empty() {
+                let items = added
+                    .iter()
+                    .map(serde_yaml::to_value)
+                    .collect::<Result<Vec<_>, _>>()?;
+                doc.append_items("rules", &items)?;
+            }
+        }
+
+        let yaml = doc.to_string();
+        let reparsed: RoutingConfig =
+            serde_yaml::from_str(&yaml).context("Edited routing config does not parse")?;
+        if reparsed != *self {
+            anyhow::bail!("Edited routing config does not match the expected content");
+        }
+
+        Ok(yaml)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        check_version(self.version).context("Invalid routing config")?;
+
+        let keys = self.addresses.keys().chain(self.actions.keys());
+        for local_part in keys {
+            if local_part.is_empty() {
+                anyhow::bail!("Invalid routing config: empty address");
+            }
+            if local_part.contains('@') || local_part.contains(char::is_whitespace) {
+                anyhow::bail!(
+                    "Invalid routing config: {:?} must be a local part without '@' or spaces",
+                    local_part
+                );
+            }
+            if local_part.to_lowercase() != *local_part {
+                anyhow::bail!("Invalid routing config: {:?} must be lowercase", local_part);
+            }
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            rule.when
+                .validate()
+                .with_context(|| format!("Invalid routing config: {}", rule.label(index)))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_protected(&self, local_part: &str) -> bool {
+        self.addresses
+            .get(local_part)
+            .is_some_and(|entry| entry.protected)
+    }
+
+    pub fn priority(&self, local_part: &str) -> i32 {
+        self.addresses
+            .get(local_part)
+            .map_or(0, |entry| entry.priority)
+    }
+
     pub fn is_allowed(&self, local_part: &str) -> bool {
-        self.addresses.get(local_part).copied().unwrap_or(true)
+        self.addresses
+            .get(local_part)
+            .is_none_or(|entry| entry.allowed)
     }
 
-    pub fn add_address(&mut self, local_part: String) {
-        self.addresses.entry(local_part).or_insert(true);
+    pub fn action_for(&self, local_part: &str) -> Action {
+        self.actions.get(local_part).copied().unwrap_or_default()
     }
 
-    pub fn update_date(&mut self, date: DateTime<Utc>) {
-        self.updated_date = date;
+    /// Adds an allowed address; returns false if it was already known.
+    pub fn add_address(&mut self, local_part: String) -> bool {
+        if self.addresses.contains_key(&local_part) {
+            return false;
+        }
+        self.addresses.insert(local_part, true.into());
+        true
+    }
+
+    pub fn set_allowed(&mut self, local_part: &str, allowed: bool) {
+        self.addresses
+            .entry(local_part.to_lowercase())
+            .or_insert_with(|| true.into())
+            .allowed = allowed;
+    }
+
+    pub fn set_note(&mut self, local_part: &str, note: Option<String>) {
+        self.addresses
+            .entry(local_part.to_lowercase())
+            .or_insert_with(|| true.into())
+            .note = note.filter(|n| !n.is_empty());
+    }
+
+    pub fn set_labels(&mut self, local_part: &str, labels: BTreeSet<String>) {
+        self.addresses
+            .entry(local_part.to_lowercase())
+            .or_insert_with(|| true.into())
+            .labels = labels;
+    }
+
+    pub fn set_action(&mut self, local_part: &str, action: Action) {
+        let local_part = local_part.to_lowercase();
+        if action == Action::default() {
+            self.actions.remove(&local_part);
+        } else {
+            self.actions.insert(local_part, action);
+        }
     }
 }
 
+/// Rejects files written by a newer version of the router.
+pub fn check_version(version: u32) -> Result<()> {
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "version {} is newer than the supported version {}; upgrade gmail_router",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Checks that `domain` is a host name such as `example.com`.
+pub fn validate_domain(domain: &str) -> Result<()> {
+    if domain.contains('@') {
+        anyhow::bail!(
+            "domain {:?} must be a bare domain like example.com, without '@'",
+            domain
+        );
+    }
+    if domain.len() > 253 || !domain.contains('.') {
+        anyhow::bail!("domain {:?} is not a valid domain name", domain);
+    }
+    for label in domain.split('.') {
+        let valid = (1..=63).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-');
+        if !valid {
+            anyhow::bail!("domain {:?} is not a valid domain name", domain);
+        }
+    }
+    Ok(())
+}
+
+fn sync_section<V: Serialize + PartialEq>(
+    doc: &mut YamlDocument,
+    section: &str,
+    previous: &BTreeMap<String, V>,
+    current: &BTreeMap<String, V>,
+) -> Result<()> {
+    for key in previous.keys().filter(|k| !current.contains_key(*k)) {
+        doc.remove_entry(section, key)?;
+    }
+    for (key, value) in current {
+        if previous.get(key) != Some(value) {
+            doc.set_entry(section, key, &serde_yaml::to_value(value)?)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     fn test_is_allowed_default() {
@@ -89,11 +786,270 @@ mod tests {
     #[test]
     fn test_is_allowed_explicit() {
         let mut config = RoutingConfig::default();
-        config.addresses.insert("allowed".to_string(), true);
-        config.addresses.insert("blocked".to_string(), false);
+        config.addresses.insert("allowed".to_string(), true.into());
+        config.addresses.insert("blocked".to_string(), false.into());
 
         assert!(config.is_allowed("allowed"));
         assert!(!config.is_allowed("blocked"));
         assert!(config.is_allowed("unknown"));
     }
+
+    #[test]
+    fn test_action_for() {
+        let mut config = RoutingConfig::default();
+        config.set_action("Spammy", Action::Spam);
+
+        assert_eq!(config.action_for("spammy"), Action::Spam);
+        assert_eq!(config.action_for("other"), Action::Delete);
+
+        config.set_action("spammy", Action::Delete);
+        assert!(config.actions.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = RoutingConfig::default();
+        config.addresses.insert("shop".to_string(), false.into());
+        assert!(config.validate().is_ok());
+
+        config.addresses.insert("Shop".to_string(), false.into());
+        assert!(config.validate().is_err());
+
+        let mut config = RoutingConfig::default();
+        config
+            .addresses
+            .insert("shop@example.com".to_string(), false.into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_update_keeps_concurrent_changes() {
+        let path = std::env::temp_dir().join(format!(
+            "gmail_router_test_routing_{}.yaml",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        RoutingConfig::update(&path, |c| {
+            c.add_address("first".to_string());
+        })
+        .unwrap();
+        RoutingConfig::update(&path, |c| c.set_allowed("second", false)).unwrap();
+
+        let config = RoutingConfig::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_file(path.with_extension("yaml.lock"));
+
+        assert!(config.is_allowed("first"));
+        assert!(!config.is_allowed("second"));
+    }
+
+    #[test]
+    fn test_update_preserves_comments() {
+        let path = std::env::temp_dir().join(format!(
+            "gmail_router_test_comments_{}.yaml",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            "# my aliases\naddresses:\n  zeta: true  # newsletter\n  alpha: false\n",
+        )
+        .unwrap();
+
+        RoutingConfig::update(&path, |c| {
+            c.add_address("new2".to_string());
+            c.add_address("new1".to_string());
+            c.set_allowed("alpha", true);
+        })
+        .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_file(path.with_extension("yaml.lock"));
+
+        assert_eq!(
+            contents,
+            "# my aliases\naddresses:\n  zeta: true  # newsletter\n  alpha: true\n  new1: true\n  new2: true\n"
+        );
+    }
+
+    #[test]
+    fn test_address_entry_yaml() {
+        let yaml = "version: 1\naddresses:\n  me:\n    allowed: true\n    protected: true\n  plain: false\n";
+        let config: RoutingConfig = serde_yaml::from_str(yaml).unwrap();
+
+        assert!(!config.is_allowed("plain"));
+        assert!(config.is_protected("me"));
+        assert_eq!(serde_yaml::to_string(&config).unwrap(), yaml);
+    }
+
+    #[test]
+    fn test_unknown_fields_are_located() {
+        let yaml = "addresses:\n  shop:\n    alowed: false\n";
+        let error = serde_yaml::from_str::<RoutingConfig>(yaml)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `alowed`"), "{}", error);
+        assert!(error.contains("line 3 column 5"), "{}", error);
+
+        let path = temp_config(
+            "google_credentials_path: secret.json\n\
+             domain: example.com\n\
+             check_interval_second: 60\n\
+             start_date: 2024-01-01T00:00:00Z\n",
+        );
+        let mut flags = ConfigOverrides::default();
+        flags.set("check_interval_seconds", "60");
+        let error = CredentialsConfig::load_with_overrides(&path, &[&flags]).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        let error = format!("{:#}", error);
+        assert!(
+            error.contains("unknown field `check_interval_second`"),
+            "{}",
+            error
+        );
+        assert!(error.contains("line 3 column 1"), "{}", error);
+    }
+
+    #[test]
+    fn test_validate_credentials() {
+        let mut flags = ConfigOverrides::default();
+        flags.set("google_credentials_path", "secret.json");
+        flags.set("domain", "example.com");
+        flags.set("check_interval_seconds", "3600");
+        flags.set("start_date", "2024-01-01T00:00:00Z");
+        let missing = std::env::temp_dir().join("gmail_router_test_missing.yaml");
+        let load = |key: &str, value: &str| {
+            let mut set = ConfigOverrides::default();
+            set.set(key, value);
+            CredentialsConfig::load_with_overrides(&missing, &[&flags, &set])
+                .map_err(|e| format!("{:#}", e))
+        };
+
+        assert!(load("domain", "mail.example.co.uk").is_ok());
+        assert!(load("domain", "me@example.com")
+            .unwrap_err()
+            .contains("without '@'"));
+        assert!(load("domain", "example").is_err());
+        assert!(load("domain", "-bad.example.com").is_err());
+        assert!(load("check_interval_seconds", "0")
+            .unwrap_err()
+            .contains("at least 10"));
+        assert!(load("start_date", "2999-01-01T00:00:00Z")
+            .unwrap_err()
+            .contains("in the future"));
+        assert!(load("version", "2").unwrap_err().contains("newer"));
+    }
+
+    #[test]
+    fn test_note_and_labels_yaml() {
+        let mut config = RoutingConfig::default();
+        config.set_note("Shop", Some("used for Amazon".to_string()));
+        config.set_labels("shop", ["shopping".to_string()].into());
+        config.set_allowed("plain", false);
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert!(yaml.contains("  plain: false\n"));
+        assert!(yaml.contains(
+            "  shop:\n    allowed: true\n    note: used for Amazon\n    labels:\n    - shopping\n"
+        ));
+
+        config.set_note("shop", None);
+        config.set_labels("shop", BTreeSet::new());
+        assert_eq!(config.addresses["shop"], AddressEntry::from(true));
+    }
+
+    fn temp_config(contents: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "gmail_router_test_{}_{}.yaml",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_overrides_from_vars() {
+        let overrides = ConfigOverrides::from_vars(vec![
+            ("GMAIL_ROUTER_DOMAIN".to_string(), "env.com".to_string()),
+            ("GMAIL_ROUTER_CONFIG_DIR".to_string(), "/tmp".to_string()),
+            (
+                "GMAIL_ROUTER_SEARCH__QUERY".to_string(),
+                "in:inbox".to_string(),
+            ),
+            ("GMAIL_ROUTER_FOO".to_string(), "1".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ]);
+
+        assert_eq!(
+            overrides.values,
+            vec![
+                ("domain".to_string(), "env.com".to_string()),
+                ("search.query".to_string(), "in:inbox".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_env_keys_are_fields() {
+        for key in ENV_KEYS {
+            let error =
+                serde_yaml::from_str::<CredentialsConfig>(&format!("{}: null", key)).unwrap_err();
+            assert!(!error.to_string().contains("unknown field"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_override_precedence() {
+        let path = temp_config(
+            "google_credentials_path: \"secret.json\"\n\
+             domain: \"file.com\"\n\
+             check_interval_seconds: 60\n\
+             start_date: \"2024-01-01T00:00:00Z\"\n",
+        );
+
+        let env = ConfigOverrides::from_vars(vec![
+            ("GMAIL_ROUTER_DOMAIN".to_string(), "env.com".to_string()),
+            (
+                "GMAIL_ROUTER_CHECK_INTERVAL_SECONDS".to_string(),
+                "120".to_string(),
+            ),
+        ]);
+        let mut flags = ConfigOverrides::default();
+        flags.set("domain", "flag.com");
+
+        let config = CredentialsConfig::load_with_overrides(&path, &[&env, &flags]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.google_credentials_path, "secret.json");
+        assert_eq!(config.domain, "flag.com");
+        assert_eq!(config.check_interval_seconds, 120);
+    }
+
+    #[test]
+    fn test_overrides_without_file() {
+        let mut flags = ConfigOverrides::default();
+        flags.set("google_credentials_path", "secret.json");
+        flags.set("domain", "example.com");
+        flags.set("check_interval_seconds", "3600");
+        flags.set("start_date", "2024-01-01T00:00:00Z");
+
+        let missing = std::env::temp_dir().join("gmail_router_test_missing.yaml");
+        let config = CredentialsConfig::load_with_overrides(&missing, &[&flags]).unwrap();
+        assert_eq!(config.domain, "example.com");
+
+        assert!(CredentialsConfig::load_with_overrides(&missing, &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            ConfigOverrides::parse_assignment("domain=a=b").unwrap(),
+            ("domain".to_string(), "a=b".to_string())
+        );
+        assert!(ConfigOverrides::parse_assignment("domain").is_err());
+    }
 }
@@ -0,0 +1,156 @@
+//! Reads raw RFC 5322 messages (.eml files) into the structure the Gmail API
+//! returns, so the routing code can look at local mail the same way.
+
+use anyhow::{Context, Result};
+use google_gmail1::api::{Message, MessagePart, MessagePartBody, MessagePartHeader};
+use mail_parser::{MessageParser, MimeHeaders, PartType};
+
+/// Parses a raw message. Non-text leaf parts are given a placeholder
+/// `attachmentId`, as Gmail does for attachment data it does not inline.
+pub fn parse(raw: &[u8]) -> Result<Message> {
+    let parsed = MessageParser::default()
+        .parse(raw)
+        .context("Not an RFC 5322 message")?;
+    let root = convert_part(&parsed, 0).context("Message has no parts")?;
+    if root.headers.as_ref().is_none_or(|h| h.is_empty()) {
+        anyhow::bail!("Message has no headers");
+    }
+
+    Ok(Message {
+        internal_date: parsed.date().map(|date| date.to_timestamp() * 1000),
+        size_estimate: i32::try_from(raw.len()).ok(),
+        payload: Some(root),
+        ..Default::default()
+    })
+}
+
+fn convert_part(message: &mail_parser::Message, index: u32) -> Option<MessagePart> {
+    let part = message.parts.get(index as usize)?;
+    let raw = message.raw_message();
+
+    let headers = part
+        .headers
+        .iter()
+        .map(|header| {
+            let value = match header.value.as_text() {
+                Some(text) => text.to_string(),
+                None => unfold(
+                    raw.get(header.offset_start as usize..header.offset_end as usize)
+                        .unwrap_or_default(),
+                ),
+            };
+            MessagePartHeader {
+                name: Some(header.name().to_string()),
+                value: Some(value),
+            }
+        })
+        .collect();
+
+    let mime_type = match part.content_type() {
+        Some(content_type) => match content_type.subtype() {
+            Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+            None => content_type.ctype().to_string(),
+        },
+        None => match part.body {
+            PartType::Multipart(_) => "multipart/mixed",
+            PartType::Message(_) => "message/rfc822",
+            PartType::Binary(_) | PartType::InlineBinary(_) => "application/octet-stream",
+            PartType::Text(_) | PartType::Html(_) => "text/plain",
+        }
+        .to_string(),
+    }
+    .to_lowercase();
+
+    let mut body = MessagePartBody {
+        size: i32::try_from(part.contents().len()).ok(),
+        ..Default::default()
+    };
+    let mut parts = None;
+    match &part.body {
+        PartType::Text(text) | PartType::Html(text) => {
+            body.data = Some(text.as_bytes().to_vec());
+        }
+        PartType::Binary(_) | PartType::InlineBinary(_) | PartType::Message(_) => {
+            body.attachment_id = Some(format!("part{}", index));
+        }
+        PartType::Multipart(children) => {
+            parts = Some(
+                children
+                    .iter()
+                    .filter_map(|&child| convert_part(message, child))
+                    .collect(),
+            );
+        }
+    }
+
+    Some(MessagePart {
+        part_id: Some(index.to_string()),
+        mime_type: Some(mime_type),
+        filename: Some(part.attachment_name().unwrap_or_default().to_string()),
+        headers: Some(headers),
+        body: Some(body),
+        parts,
+    })
+}
+
+/// A raw header value with folding line breaks removed.
+fn unfold(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw)
+        .split(['\r', '\n'])
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{extract_recipients, MessageFacts};
+
+    const MESSAGE: &str = "From: Shop <orders@shop.com>\r\n\
+To: Me <shop@example.com>,\r\n\
+ friend@other.com\r\n\
+Cc: news@example.com\r\n\
+Subject: =?utf-8?q?Your_receipt?=\r\n\
+Date: Mon, 1 Jan 2024 12:00:00 +0000\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Thanks for your order\r\n\
+--b\r\n\
+Content-Type: application/pdf\r\n\
+Content-Disposition: attachment; filename=receipt.pdf\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0=\r\n\
+--b--\r\n";
+
+    #[test]
+    fn test_parse_eml() {
+        let message = parse(MESSAGE.as_bytes()).unwrap();
+        let facts = MessageFacts::from_message(&message, "example.com").unwrap();
+
+        assert_eq!(
+            extract_recipients(&message, "example.com").unwrap(),
+            vec!["shop"]
+        );
+        assert_eq!(facts.from.as_deref(), Some("orders@shop.com"));
+        assert_eq!(facts.subject.as_deref(), Some("Your receipt"));
+        assert!(facts.body.contains("Thanks for your order"));
+        assert_eq!(facts.attachments, vec!["application/pdf"]);
+        assert_eq!(
+            facts.received.unwrap().to_rfc3339(),
+            "2024-01-01T12:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_non_messages() {
+        assert!(parse(b"").is_err());
+    }
+}
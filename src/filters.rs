@@ -0,0 +1,507 @@
+//! Native Gmail filters: exporting blocked addresses as server-side filters, and
+//! importing existing filters as routing rules.
+//!
+//! Exported filters act on arrival, before the router polls. Their IDs are kept in
+//! state.json so a sync only ever deletes filters the router created.
+
+use crate::config::{Action, ConflictPolicy, RoutingConfig};
+use crate::gmail::GmailClient;
+use crate::quarantine::QUARANTINE_LABEL;
+use crate::rules::{Condition, Rule, RuleAction, Size};
+use crate::state::State;
+use anyhow::{bail, Context, Result};
+use google_gmail1::api::{Filter, FilterAction, FilterCriteria};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use tracing::info;
+
+/// `filters` section of credentials.yaml.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FiltersConfig {
+    /// Keep the exported Gmail filters in line with routing.yaml after every cycle
+    #[serde(default)]
+    pub sync: bool,
+}
+
+/// The parts of a Gmail filter that export compares; Gmail filters cannot be
+/// edited, so any difference means delete and create.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FilterShape {
+    /// Full recipient address
+    pub to: String,
+    pub negated_query: Option<String>,
+    pub add_label_ids: BTreeSet<String>,
+    pub remove_label_ids: BTreeSet<String>,
+}
+
+impl FilterShape {
+    /// The shape of an existing filter, if it is one export could have made.
+    pub fn of(filter: &Filter) -> Option<Self> {
+        let criteria = filter.criteria.as_ref()?;
+        let action = filter.action.as_ref()?;
+        let only_to = criteria.from.is_none()
+            && criteria.subject.is_none()
+            && criteria.query.is_none()
+            && criteria.has_attachment.is_none()
+            && criteria.size.is_none()
+            && action.forward.is_none();
+        if !only_to {
+            return None;
+        }
+
+        Some(Self {
+            to: criteria.to.clone()?.to_lowercase(),
+            negated_query: criteria.negated_query.clone(),
+            add_label_ids: action.add_label_ids.iter().flatten().cloned().collect(),
+            remove_label_ids: action.remove_label_ids.iter().flatten().cloned().collect(),
+        })
+    }
+
+    pub fn to_filter(&self) -> Filter {
+        Filter {
+            criteria: Some(FilterCriteria {
+                to: Some(self.to.clone()),
+                negated_query: self.negated_query.clone(),
+                ..Default::default()
+            }),
+            action: Some(FilterAction {
+                add_label_ids: Some(self.add_label_ids.iter().cloned().collect()),
+                remove_label_ids: Some(self.remove_label_ids.iter().cloned().collect()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// A blocked address to export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportedAddress {
+    pub local_part: String,
+    pub action: Action,
+}
+
+/// What export would do for a routing config.
+#[derive(Debug, Default)]
+pub struct ExportPlan {
+    pub addresses: Vec<ExportedAddress>,
+    /// Mail to these addresses is never filtered
+    pub protected: Vec<String>,
+    /// Things the filters do differently from the router
+    pub warnings: Vec<String>,
+}
+
+impl ExportPlan {
+    /// Every blocked address that is not protected. Filters act on each recipient
+    /// alone, so only the `any_blocked` conflict policy can be exported.
+    pub fn new(routing_config: &RoutingConfig) -> Result<Self> {
+        if routing_config.conflict_policy != ConflictPolicy::AnyBlocked {
+            bail!("Only the any_blocked conflict policy can be exported as Gmail filters");
+        }
+
+        let mut plan = Self::default();
+        for (local_part, entry) in &routing_config.addresses {
+            if entry.protected {
+                plan.protected.push(local_part.clone());
+            } else if !entry.allowed {
+                plan.addresses.push(ExportedAddress {
+                    local_part: local_part.clone(),
+                    action: routing_config.action_for(local_part),
+                });
+            }
+        }
+
+        if plan.addresses.iter().any(|a| a.action == Action::Delete) {
+            plan.warnings.push(
+                "Gmail filters cannot delete mail; deleted addresses are exported as trash"
+                    .to_string(),
+            );
+        }
+        for (index, rule) in routing_config.rules.iter().enumerate() {
+            if rule.action == RuleAction::Allow {
+                plan.warnings.push(format!(
+                    "{} keeps mail the router would route, but the filters act before it",
+                    rule.label(index)
+                ));
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// The filters for `domain`. `quarantine_label` is the ID of the quarantine
+    /// label, needed if any address is quarantined.
+    pub fn shapes(&self, domain: &str, quarantine_label: Option<&str>) -> Result<Vec<FilterShape>> {
+        let negated_query = (!self.protected.is_empty()).then(|| {
+            let recipients: Vec<_> = self
+                .protected
+                .iter()
+                .map(|local_part| format!("to:{}@{}", local_part, domain))
+                .collect();
+            format!("{{{}}}", recipients.join(" "))
+        });
+
+        self.addresses
+            .iter()
+            .map(|address| {
+                let label = match address.action {
+                    Action::Delete | Action::Trash => "TRASH",
+                    Action::Spam => "SPAM",
+                    Action::Quarantine => {
+                        quarantine_label.context("The quarantine label ID is needed")?
+                    }
+                };
+                Ok(FilterShape {
+                    to: format!("{}@{}", address.local_part, domain),
+                    negated_query: negated_query.clone(),
+                    add_label_ids: BTreeSet::from([label.to_string()]),
+                    remove_label_ids: BTreeSet::from(["INBOX".to_string()]),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Result of an export.
+#[derive(Debug, Default, Serialize)]
+pub struct ExportReport {
+    /// Recipient addresses of the created filters
+    pub created: Vec<String>,
+    /// Recipient addresses of the deleted filters
+    pub deleted: Vec<String>,
+    pub unchanged: usize,
+    pub warnings: Vec<String>,
+}
+
+/// Creates a filter for every blocked address that has none. With `prune`, also
+/// deletes exported filters that routing.yaml no longer asks for.
+pub async fn export(
+    gmail_client: &GmailClient,
+    routing_config: &RoutingConfig,
+    domain: &str,
+    state_path: &Path,
+    prune: bool,
+    dry_run: bool,
+) -> Result<ExportReport> {
+    let plan = ExportPlan::new(routing_config)?;
+    let quarantined = plan
+        .addresses
+        .iter()
+        .any(|a| a.action == Action::Quarantine);
+    let quarantine_label = match (quarantined, dry_run) {
+        (false, _) => None,
+        (true, false) => Some(gmail_client.ensure_label(QUARANTINE_LABEL).await?),
+        (true, true) => Some(
+            gmail_client
+                .find_label(QUARANTINE_LABEL)
+                .await?
+                .unwrap_or_else(|| QUARANTINE_LABEL.to_string()),
+        ),
+    };
+    let wanted = plan.shapes(domain, quarantine_label.as_deref())?;
+
+    let existing = gmail_client.list_filters().await?;
+    let existing_ids: BTreeSet<_> = existing.iter().filter_map(|f| f.id.clone()).collect();
+    let mut managed: BTreeSet<String> = State::load(state_path)?
+        .filters
+        .intersection(&existing_ids)
+        .cloned()
+        .collect();
+
+    let mut report = ExportReport {
+        warnings: plan.warnings,
+        ..Default::default()
+    };
+
+    let existing_shapes: Vec<_> = existing
+        .iter()
+        .map(|filter| (filter.id.clone(), FilterShape::of(filter)))
+        .collect();
+    for shape in &wanted {
+        if existing_shapes
+            .iter()
+            .any(|(_, s)| s.as_ref() == Some(shape))
+        {
+            report.unchanged += 1;
+            continue;
+        }
+        if !dry_run {
+            let id = gmail_client
+                .create_filter(shape.to_filter())
+                .await
+                .with_context(|| format!("Failed to export {}", shape.to))?;
+            info!("Created Gmail filter for {}", shape.to);
+            managed.insert(id);
+        }
+        report.created.push(shape.to.clone());
+    }
+
+    if prune {
+        for (id, shape) in &existing_shapes {
+            let Some(id) = id.as_ref().filter(|id| managed.contains(*id)) else {
+                continue;
+            };
+            if shape.as_ref().is_some_and(|s| wanted.contains(s)) {
+                continue;
+            }
+            if !dry_run {
+                gmail_client.delete_filter(id).await?;
+                info!("Deleted Gmail filter {}", id);
+                managed.remove(id);
+            }
+            report
+                .deleted
+                .push(shape.as_ref().map_or(id.clone(), |s| s.to.clone()));
+        }
+    }
+
+    if !dry_run {
+        State::update(state_path, |state| state.filters = managed)?;
+    }
+
+    Ok(report)
+}
+
+/// An existing Gmail filter and the rule it becomes, or why it cannot be imported.
+#[derive(Debug)]
+pub struct ImportedFilter {
+    pub id: String,
+    pub rule: Result<Rule, String>,
+}
+
+/// Name of the rule imported from a filter; also how repeated imports are skipped.
+pub fn rule_name(filter_id: &str) -> String {
+    format!("gmail filter {}", filter_id)
+}
+
+/// Converts the filters that are not exported by the router, skipping those
+/// imported before.
+pub async fn import(
+    gmail_client: &GmailClient,
+    routing_config: &RoutingConfig,
+    domain: &str,
+    state_path: &Path,
+) -> Result<Vec<ImportedFilter>> {
+    let managed = State::load(state_path)?.filters;
+    let quarantine_label = gmail_client.find_label(QUARANTINE_LABEL).await?;
+    let known: BTreeSet<_> = routing_config
+        .rules
+        .iter()
+        .filter_map(|rule| rule.name.as_deref())
+        .collect();
+
+    Ok(gmail_client
+        .list_filters()
+        .await?
+        .iter()
+        .filter_map(|filter| {
+            let id = filter.id.clone()?;
+            if managed.contains(&id) || known.contains(rule_name(&id).as_str()) {
+                return None;
+            }
+            let rule = filter_to_rule(filter, domain, quarantine_label.as_deref());
+            Some(ImportedFilter { id, rule })
+        })
+        .collect())
+}
+
+/// The routing rule doing what `filter` does.
+pub fn filter_to_rule(
+    filter: &Filter,
+    domain: &str,
+    quarantine_label: Option<&str>,
+) -> Result<Rule, String> {
+    let criteria = filter.criteria.clone().unwrap_or_default();
+    let action = filter.action.clone().unwrap_or_default();
+
+    if criteria.query.is_some() || criteria.negated_query.is_some() {
+        return Err("search queries have no rule equivalent".to_string());
+    }
+
+    let mut when = Condition::default();
+    if let Some(from) = &criteria.from {
+        let from = simple_term(from).ok_or(format!("cannot convert from {:?}", from))?;
+        match from.split_once('@') {
+            Some(("", domain)) => when.from_domain = Some(domain.to_string()),
+            Some(_) => when.from = Some(from),
+            None if from.contains('.') => when.from_domain = Some(from),
+            None => return Err(format!("cannot convert from {:?}", from)),
+        }
+    }
+    if let Some(to) = &criteria.to {
+        let local_part = simple_term(to)
+            .and_then(|to| {
+                let (local_part, to_domain) = to.split_once('@')?;
+                to_domain
+                    .eq_ignore_ascii_case(domain)
+                    .then(|| local_part.to_string())
+            })
+            .ok_or(format!("to {:?} is not an address on {}", to, domain))?;
+        when.to = Some(local_part);
+    }
+    if let Some(subject) = &criteria.subject {
+        when.subject = Some(
+            regex::escape(subject.trim())
+                .parse()
+                .map_err(|e| format!("{}", e))?,
+        );
+    }
+    if criteria.has_attachment == Some(true) {
+        when.has_attachment = Some(true);
+    }
+    if let Some(size) = criteria.size {
+        let size = Size(u64::try_from(size).map_err(|_| "negative size".to_string())?);
+        match criteria.size_comparison.as_deref() {
+            Some("larger") => when.larger_than = Some(size),
+            Some("smaller") => when.smaller_than = Some(size),
+            other => return Err(format!("unknown size comparison {:?}", other)),
+        }
+    }
+    if when.is_empty() {
+        return Err("no criteria the router can check".to_string());
+    }
+
+    if action.forward.is_some() {
+        return Err("forwarding has no rule equivalent".to_string());
+    }
+    let added = action.add_label_ids.unwrap_or_default();
+    let removed = action.remove_label_ids.unwrap_or_default();
+    let action = if added.iter().any(|l| l == "TRASH") {
+        RuleAction::Trash
+    } else if added.iter().any(|l| l == "SPAM") {
+        RuleAction::Spam
+    } else if quarantine_label.is_some_and(|q| added.iter().any(|l| l == q)) {
+        RuleAction::Quarantine
+    } else if removed.iter().any(|l| l == "SPAM") {
+        RuleAction::Allow
+    } else {
+        return Err(
+            "only trash, spam, quarantine and never-spam filters can be imported".to_string(),
+        );
+    };
+
+    Ok(Rule {
+        name: filter.id.as_deref().map(rule_name),
+        when,
+        action,
+    })
+}
+
+/// A single lowercased address or domain; `None` for Gmail search expressions.
+fn simple_term(value: &str) -> Option<String> {
+    let value = value.trim().to_lowercase();
+    let simple = !value.is_empty()
+        && !value.contains(char::is_whitespace)
+        && !value.contains(['(', ')', '{', '}', '"', '|', '*', ':']);
+    simple.then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(criteria: FilterCriteria, add: &[&str], remove: &[&str]) -> Filter {
+        let labels = |ids: &[&str]| Some(ids.iter().map(|id| id.to_string()).collect());
+        Filter {
+            id: Some("f1".to_string()),
+            criteria: Some(criteria),
+            action: Some(FilterAction {
+                add_label_ids: labels(add),
+                remove_label_ids: labels(remove),
+                forward: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_export_shapes() {
+        let config: RoutingConfig = serde_yaml::from_str(
+            "addresses:\n  shop: false\n  old: false\n  me: true\n  admin:\n    allowed: false\n    protected: true\nactions:\n  shop: spam\n",
+        )
+        .unwrap();
+
+        let plan = ExportPlan::new(&config).unwrap();
+        let shapes = plan.shapes("example.com", None).unwrap();
+        assert_eq!(plan.warnings.len(), 1);
+        assert_eq!(shapes.len(), 2);
+        assert_eq!(shapes[0].to, "old@example.com");
+        assert_eq!(
+            shapes[0].add_label_ids,
+            BTreeSet::from(["TRASH".to_string()])
+        );
+        assert_eq!(shapes[1].to, "shop@example.com");
+        assert_eq!(
+            shapes[1].add_label_ids,
+            BTreeSet::from(["SPAM".to_string()])
+        );
+        assert_eq!(
+            shapes[1].negated_query.as_deref(),
+            Some("{to:admin@example.com}")
+        );
+        assert_eq!(
+            FilterShape::of(&shapes[1].to_filter()).as_ref(),
+            Some(&shapes[1])
+        );
+
+        let mut config = config;
+        config.conflict_policy = ConflictPolicy::AllBlocked;
+        assert!(ExportPlan::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_filter_to_rule() {
+        let criteria = FilterCriteria {
+            from: Some("@Shop.com".to_string()),
+            to: Some("deals@example.com".to_string()),
+            subject: Some("50% off".to_string()),
+            ..Default::default()
+        };
+        let rule = filter_to_rule(
+            &filter(criteria, &["SPAM"], &["INBOX"]),
+            "example.com",
+            None,
+        )
+        .unwrap();
+        assert_eq!(rule.name.as_deref(), Some("gmail filter f1"));
+        assert_eq!(rule.action, RuleAction::Spam);
+        assert_eq!(rule.when.from_domain.as_deref(), Some("shop.com"));
+        assert_eq!(rule.when.to.as_deref(), Some("deals"));
+        assert_eq!(
+            serde_yaml::to_string(&rule.when.subject).unwrap(),
+            "50% off\n"
+        );
+
+        let never_spam = FilterCriteria {
+            from: Some("boss@work.example".to_string()),
+            ..Default::default()
+        };
+        let rule =
+            filter_to_rule(&filter(never_spam, &[], &["SPAM"]), "example.com", None).unwrap();
+        assert_eq!(rule.action, RuleAction::Allow);
+        assert_eq!(rule.when.from.as_deref(), Some("boss@work.example"));
+
+        let search = FilterCriteria {
+            from: Some("a@x.com OR b@y.com".to_string()),
+            ..Default::default()
+        };
+        assert!(filter_to_rule(&filter(search, &["TRASH"], &[]), "example.com", None).is_err());
+
+        let other_domain = FilterCriteria {
+            to: Some("me@other.com".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            filter_to_rule(&filter(other_domain, &["TRASH"], &[]), "example.com", None).is_err()
+        );
+
+        let label_only = FilterCriteria {
+            from: Some("news.example".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            filter_to_rule(&filter(label_only, &["Label_7"], &[]), "example.com", None).is_err()
+        );
+    }
+}
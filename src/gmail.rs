@@ -1,30 +1,169 @@
+use crate::config::{get_config_path, Action, TOKEN_CACHE_FILE};
+use crate::metrics;
+use crate::quarantine::QUARANTINE_LABEL;
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use google_gmail1::{
-    api::{ListMessagesResponse, Message},
+    api::{Filter, Label, ListMessagesResponse, Message, ModifyMessageRequest},
     hyper::{self, client::HttpConnector},
     hyper_rustls::{self, HttpsConnector},
     oauth2::{self},
     Gmail,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::path::Path;
-use tracing::{debug, info};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{debug, info, warn};
+
+/// `search` section of credentials.yaml: which mail the router looks at.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchConfig {
+    /// Gmail search fragment, e.g. `in:inbox OR in:spam`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Label IDs a message must all carry, e.g. `[INBOX, UNREAD]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub label_ids: Vec<String>,
+    /// Gmail categories, any of which matches: primary, social, promotions, updates, forums
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+    /// Also search spam and trash
+    #[serde(default)]
+    pub include_spam_trash: bool,
+}
+
+impl SearchConfig {
+    fn is_default(&self) -> bool {
+        self.query.is_none()
+            && self.label_ids.is_empty()
+            && self.categories.is_empty()
+            && !self.include_spam_trash
+    }
+
+    /// Whether a message with these label IDs is in scope, judged by its labels.
+    /// A `query` cannot be checked locally, so with one only the label and
+    /// category settings are applied.
+    pub fn covers(&self, labels: &[String]) -> bool {
+        let has = |label: &str| labels.iter().any(|l| l == label);
+
+        if self.query.is_none() && !self.include_spam_trash && (has("SPAM") || has("TRASH")) {
+            return false;
+        }
+        if self.query.is_none()
+            && self.label_ids.is_empty()
+            && self.categories.is_empty()
+            && !has("INBOX")
+        {
+            return false;
+        }
+        self.label_ids.iter().all(|label| has(label))
+            && (self.categories.is_empty()
+                || self
+                    .categories
+                    .iter()
+                    .any(|c| has(&format!("CATEGORY_{}", c.to_uppercase()))))
+    }
+
+    /// The search query for mail received since `since`. Without any settings this
+    /// is `in:inbox after:<seconds>`.
+    pub fn query(&self, since: DateTime<Utc>) -> String {
+        if self.is_default() {
+            return format!("in:inbox {}", after_filter(since));
+        }
+
+        let mut parts = Vec::new();
+        match &self.query {
+            Some(query) => parts.push(format!("({})", query)),
+            None if self.label_ids.is_empty() && self.categories.is_empty() => {
+                parts.push("in:inbox".to_string())
+            }
+            None => {}
+        }
+        if !self.categories.is_empty() {
+            let categories: Vec<_> = self
+                .categories
+                .iter()
+                .map(|c| format!("category:{}", c.to_lowercase()))
+                .collect();
+            parts.push(format!("{{{}}}", categories.join(" ")));
+        }
+        // Quarantined mail has left the inbox on purpose; a wider scope must not route it again
+        parts.push(format!("-label:{}", QUARANTINE_LABEL.replace('/', "-")));
+        parts.push(after_filter(since));
+        parts.join(" ")
+    }
+}
+
+/// Gmail `after:` filter including everything received at or after `since`.
+///
+/// Dates like `after:2024/06/12` are read in Pacific time and drop the time of
+/// day, so the filter uses epoch seconds instead. Gmail compares whole seconds and
+/// `since` may carry milliseconds, hence the one second of overlap; messages seen
+/// twice are recognised by their date.
+pub fn after_filter(since: DateTime<Utc>) -> String {
+    format!("after:{}", since.timestamp() - 1)
+}
+
+/// Scope the router needs for mail.
+const MAIL_SCOPE: &str = "https://mail.google.com/";
+
+/// Scope required to create and delete Gmail filters.
+const SETTINGS_SCOPE: &str = "https://www.googleapis.com/auth/gmail.settings.basic";
+
+/// Awaits one API call, recording its status and latency under `endpoint`.
+async fn observe<T>(
+    endpoint: &str,
+    call: impl std::future::Future<Output = google_gmail1::Result<T>>,
+) -> google_gmail1::Result<T> {
+    let started = Instant::now();
+    let result = call.await;
+    let status = match &result {
+        Ok(_) => "200".to_string(),
+        Err(e) => error_status(e),
+    };
+    metrics::registry().record_api_call(endpoint, &status, started.elapsed());
+    result
+}
+
+/// HTTP status of a failed call, or `error` if it got no response.
+fn error_status(error: &google_gmail1::Error) -> String {
+    match error {
+        google_gmail1::Error::Failure(response) => response.status().as_u16().to_string(),
+        google_gmail1::Error::BadRequest(body) => {
+            body["error"]["code"].as_u64().unwrap_or(400).to_string()
+        }
+        _ => "error".to_string(),
+    }
+}
 
 pub struct GmailClient {
     hub: Gmail<HttpsConnector<HttpConnector>>,
+    /// Label IDs by name
+    labels: Mutex<HashMap<String, String>>,
 }
 
 impl GmailClient {
     pub async fn new<P: AsRef<Path>>(credentials_path: P) -> Result<Self> {
+        Self::connect(credentials_path, &[MAIL_SCOPE]).await
+    }
+
+    /// Authorizes every scope the router uses, asking for consent again if the
+    /// cached token lacks one.
+    pub async fn login<P: AsRef<Path>>(credentials_path: P) -> Result<Self> {
+        Self::connect(credentials_path, &[MAIL_SCOPE, SETTINGS_SCOPE]).await
+    }
+
+    async fn connect<P: AsRef<Path>>(credentials_path: P, scopes: &[&str]) -> Result<Self> {
         info!("Initializing Gmail client");
 
         let secret = oauth2::read_application_secret(credentials_path)
             .await
             .context("Failed to read OAuth2 credentials")?;
 
-        let mut path = dirs::config_dir().expect("Cannot find config dir");
-        path.push("gmail_router");
-        std::fs::create_dir_all(&path).expect("Cannot create config dir");
-        path.push("token_cache.json");
+        let path = get_config_path(TOKEN_CACHE_FILE);
         let auth = oauth2::InstalledFlowAuthenticator::builder(
             secret,
             oauth2::InstalledFlowReturnMethod::HTTPPortRedirect(14500),
@@ -34,15 +173,11 @@ impl GmailClient {
         .await
         .context("Failed to create authenticator")?;
 
-        let scopes = &["https://mail.google.com/"];
         let token = auth
             .token(scopes)
             .await
             .context("Failed to obtain access token")?;
-        println!("Token obtained: {:?}", token.token().is_some());
-        if let Some(t) = token.token() {
-            println!("Token value: {}", t);
-        }
+        debug!("Token obtained: {:?}", token.token().is_some());
         let https = hyper_rustls::HttpsConnectorBuilder::new()
             .with_native_roots()
             .context("Failed to load native roots")?
@@ -53,12 +188,37 @@ impl GmailClient {
         let client = hyper::Client::builder().build(https);
         let hub = Gmail::new(client, auth);
 
-        Ok(Self { hub })
+        Ok(Self {
+            hub,
+            labels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn list_messages(
+        &self,
+        scope: &SearchConfig,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<String>> {
+        info!("Fetching messages since {}", since);
+        self.list_message_ids(
+            &scope.query(since),
+            &scope.label_ids,
+            scope.include_spam_trash,
+        )
+        .await
     }
 
-    pub async fn list_messages(&self, after_date: &str) -> Result<Vec<String>> {
-        info!("Fetching messages after {}", after_date);
+    /// IDs of all messages matching a Gmail search query and carrying every label in `label_ids`.
+    pub async fn search(&self, query: &str, label_ids: &[String]) -> Result<Vec<String>> {
+        self.list_message_ids(query, label_ids, false).await
+    }
 
+    async fn list_message_ids(
+        &self,
+        query: &str,
+        label_ids: &[String],
+        include_spam_trash: bool,
+    ) -> Result<Vec<String>> {
         let mut all_message_ids = Vec::new();
         let mut page_token: Option<String> = None;
 
@@ -68,19 +228,23 @@ impl GmailClient {
                 .users()
                 .messages_list("me")
                 .add_scope("https://mail.google.com/");
-            request = request.q(&format!("in:inbox after:{}", after_date));
+            if !query.is_empty() {
+                request = request.q(query);
+            }
+            for label_id in label_ids {
+                request = request.add_label_ids(label_id);
+            }
+            if include_spam_trash {
+                request = request.include_spam_trash(true);
+            }
 
             if let Some(token) = page_token {
                 request = request.page_token(&token);
             }
 
-            let result: ListMessagesResponse = match request.doit().await {
-                Ok(res) => res.1,
-                Err(e) => {
-                    eprintln!("Gmail API error: {:#?}", e);
-                    return Err(e.into());
-                }
-            };
+            let (_, result): (_, ListMessagesResponse) = observe("messages.list", request.doit())
+                .await
+                .context("Failed to list messages")?;
 
             if let Some(messages) = result.messages {
                 for msg in messages {
@@ -101,48 +265,635 @@ impl GmailClient {
         Ok(all_message_ids)
     }
 
+    /// IDs of threads in the search scope with messages since `since`.
+    pub async fn list_threads(
+        &self,
+        scope: &SearchConfig,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<String>> {
+        info!("Fetching threads since {}", since);
+
+        let mut thread_ids = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .hub
+                .users()
+                .threads_list("me")
+                .add_scope("https://mail.google.com/")
+                .q(&scope.query(since))
+                .include_spam_trash(scope.include_spam_trash);
+            for label_id in &scope.label_ids {
+                request = request.add_label_ids(label_id);
+            }
+
+            if let Some(token) = page_token {
+                request = request.page_token(&token);
+            }
+
+            let (_, result) = observe("threads.list", request.doit())
+                .await
+                .context("Failed to list threads")?;
+            thread_ids.extend(result.threads.into_iter().flatten().filter_map(|t| t.id));
+
+            page_token = result.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        debug!("Found {} threads", thread_ids.len());
+        Ok(thread_ids)
+    }
+
+    /// All messages of a thread, oldest first.
+    pub async fn get_thread(&self, thread_id: &str) -> Result<Vec<Message>> {
+        let (_, thread) = observe(
+            "threads.get",
+            self.hub
+                .users()
+                .threads_get("me", thread_id)
+                .add_scope("https://mail.google.com/")
+                .format("full")
+                .doit(),
+        )
+        .await
+        .context("Failed to get thread")?;
+
+        Ok(thread.messages.unwrap_or_default())
+    }
+
     pub async fn get_message(&self, message_id: &str) -> Result<Message> {
-        let result = self
-            .hub
-            .users()
-            .messages_get("me", message_id)
-            .add_scope("https://mail.google.com/")
-            .format("full")
-            .doit()
-            .await
-            .context("Failed to get message")?;
+        let result = observe(
+            "messages.get",
+            self.hub
+                .users()
+                .messages_get("me", message_id)
+                .add_scope("https://mail.google.com/")
+                .format("full")
+                .doit(),
+        )
+        .await
+        .context("Failed to get message")?;
 
         Ok(result.1)
     }
 
+    /// The message in RFC 822 form.
+    pub async fn get_raw_message(&self, message_id: &str) -> Result<Vec<u8>> {
+        let (_, message) = observe(
+            "messages.get",
+            self.hub
+                .users()
+                .messages_get("me", message_id)
+                .add_scope("https://mail.google.com/")
+                .format("raw")
+                .doit(),
+        )
+        .await
+        .context("Failed to get raw message")?;
+
+        message.raw.context("Message has no raw content")
+    }
+
+    /// Adds an RFC 822 message to the mailbox with the given labels and returns its ID.
+    pub async fn insert_message(&self, raw: Vec<u8>, label_ids: Vec<String>) -> Result<String> {
+        let request = Message {
+            label_ids: Some(label_ids),
+            ..Default::default()
+        };
+        let (_, inserted) = observe(
+            "messages.insert",
+            self.hub
+                .users()
+                .messages_insert(request, "me")
+                .add_scope("https://mail.google.com/")
+                .internal_date_source("dateHeader")
+                .upload(std::io::Cursor::new(raw), "message/rfc822".parse().unwrap()),
+        )
+        .await
+        .context("Failed to insert message")?;
+
+        inserted.id.context("Inserted message has no ID")
+    }
+
+    /// Current label IDs of a message.
+    pub async fn get_labels(&self, message_id: &str) -> Result<BTreeSet<String>> {
+        let (_, message) = observe(
+            "messages.get",
+            self.hub
+                .users()
+                .messages_get("me", message_id)
+                .add_scope("https://mail.google.com/")
+                .format("minimal")
+                .doit(),
+        )
+        .await
+        .context("Failed to get message labels")?;
+
+        Ok(message.label_ids.unwrap_or_default().into_iter().collect())
+    }
+
     pub async fn delete_message(&self, message_id: &str) -> Result<()> {
-        self.hub
-            .users()
-            .messages_delete("me", message_id)
-            .add_scope("https://mail.google.com/")
-            .doit()
-            .await
-            .context("Failed to delete message")?;
+        observe(
+            "messages.delete",
+            self.hub
+                .users()
+                .messages_delete("me", message_id)
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .context("Failed to delete message")?;
 
         debug!("Deleted message {}", message_id);
         Ok(())
     }
 
     pub async fn move_message_to_spam(&self, message_id: &str) -> Result<()> {
-        let req = google_gmail1::api::ModifyMessageRequest {
+        let req = ModifyMessageRequest {
             add_label_ids: Some(vec!["SPAM".to_string()]),
             remove_label_ids: Some(vec!["INBOX".to_string()]),
         };
 
-        self.hub
-            .users()
-            .messages_modify(req, "me", message_id)
-            .add_scope("https://mail.google.com/")
-            .doit()
-            .await
-            .context("Failed to move message to spam")?;
+        observe(
+            "messages.modify",
+            self.hub
+                .users()
+                .messages_modify(req, "me", message_id)
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .context("Failed to move message to spam")?;
 
         debug!("Moved message to spam {}", message_id);
         Ok(())
     }
+
+    pub async fn trash_message(&self, message_id: &str) -> Result<()> {
+        observe(
+            "messages.trash",
+            self.hub
+                .users()
+                .messages_trash("me", message_id)
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .context("Failed to move message to trash")?;
+
+        debug!("Moved message to trash {}", message_id);
+        Ok(())
+    }
+
+    pub async fn modify_labels(
+        &self,
+        message_id: &str,
+        add: Vec<String>,
+        remove: Vec<String>,
+    ) -> Result<()> {
+        let req = ModifyMessageRequest {
+            add_label_ids: Some(add),
+            remove_label_ids: Some(remove),
+        };
+
+        observe(
+            "messages.modify",
+            self.hub
+                .users()
+                .messages_modify(req, "me", message_id)
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .context("Failed to modify message labels")?;
+
+        Ok(())
+    }
+
+    /// Every label of the mailbox. Refreshes the label ID cache.
+    pub async fn list_labels(&self) -> Result<Vec<Label>> {
+        let (_, response) = observe(
+            "labels.list",
+            self.hub
+                .users()
+                .labels_list("me")
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .context("Failed to list labels")?;
+
+        let labels = response.labels.unwrap_or_default();
+        let mut cache = self.labels.lock().unwrap();
+        cache.clear();
+        for label in &labels {
+            if let (Some(id), Some(name)) = (&label.id, &label.name) {
+                cache.insert(name.clone(), id.clone());
+            }
+        }
+        Ok(labels)
+    }
+
+    /// ID of the user label called `name`, if it exists.
+    pub async fn find_label(&self, name: &str) -> Result<Option<String>> {
+        if let Some(id) = self.labels.lock().unwrap().get(name) {
+            return Ok(Some(id.clone()));
+        }
+
+        self.list_labels().await?;
+        Ok(self.labels.lock().unwrap().get(name).cloned())
+    }
+
+    /// ID of the user label called `name`, creating the label if needed. The
+    /// parents of a nested `a/b` label are created first, so Gmail shows it nested.
+    pub async fn ensure_label(&self, name: &str) -> Result<String> {
+        if let Some(id) = self.find_label(name).await? {
+            return Ok(id);
+        }
+        if let Some((parent, _)) = name.rsplit_once('/') {
+            Box::pin(self.ensure_label(parent)).await?;
+        }
+
+        let label = Label {
+            name: Some(name.to_string()),
+            label_list_visibility: Some("labelShow".to_string()),
+            message_list_visibility: Some("show".to_string()),
+            ..Default::default()
+        };
+        let (_, created) = observe(
+            "labels.create",
+            self.hub
+                .users()
+                .labels_create(label, "me")
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .with_context(|| format!("Failed to create label {}", name))?;
+
+        let id = created.id.context("Created label has no ID")?;
+        info!("Created label {}", name);
+        self.labels
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), id.clone());
+        Ok(id)
+    }
+
+    /// Renames a label, creating the parents of the new name first.
+    pub async fn rename_label(&self, label_id: &str, name: &str) -> Result<()> {
+        if let Some((parent, _)) = name.rsplit_once('/') {
+            self.ensure_label(parent).await?;
+        }
+
+        let label = Label {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        observe(
+            "labels.patch",
+            self.hub
+                .users()
+                .labels_patch(label, "me", label_id)
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .with_context(|| format!("Failed to rename label to {}", name))?;
+
+        info!("Renamed label to {}", name);
+        let mut labels = self.labels.lock().unwrap();
+        labels.retain(|_, id| id != label_id);
+        labels.insert(name.to_string(), label_id.to_string());
+        Ok(())
+    }
+
+    /// Moves a message out of the inbox into the quarantine label.
+    pub async fn quarantine_message(&self, message_id: &str) -> Result<()> {
+        let label_id = self.ensure_label(QUARANTINE_LABEL).await?;
+        self.modify_labels(message_id, vec![label_id], vec!["INBOX".to_string()])
+            .await
+            .context("Failed to quarantine message")?;
+
+        debug!("Quarantined message {}", message_id);
+        Ok(())
+    }
+
+    /// Returns a quarantined message to the inbox.
+    pub async fn release_message(&self, message_id: &str) -> Result<()> {
+        let label_id = self.ensure_label(QUARANTINE_LABEL).await?;
+        self.modify_labels(message_id, vec!["INBOX".to_string()], vec![label_id])
+            .await
+            .context("Failed to release message")?;
+
+        debug!("Released message {}", message_id);
+        Ok(())
+    }
+
+    pub async fn untrash_message(&self, message_id: &str) -> Result<()> {
+        observe(
+            "messages.untrash",
+            self.hub
+                .users()
+                .messages_untrash("me", message_id)
+                .add_scope("https://mail.google.com/")
+                .doit(),
+        )
+        .await
+        .context("Failed to restore message from trash")?;
+
+        debug!("Restored message from trash {}", message_id);
+        Ok(())
+    }
+
+    pub async fn apply_action(&self, message_id: &str, action: Action) -> Result<()> {
+        match action {
+            Action::Delete => self.delete_message(message_id).await,
+            Action::Trash => self.trash_message(message_id).await,
+            Action::Spam => self.move_message_to_spam(message_id).await,
+            Action::Quarantine => self.quarantine_message(message_id).await,
+        }
+    }
+
+    pub async fn list_filters(&self) -> Result<Vec<Filter>> {
+        let (_, response) = observe(
+            "settings.filters.list",
+            self.hub
+                .users()
+                .settings_filters_list("me")
+                .add_scope(SETTINGS_SCOPE)
+                .doit(),
+        )
+        .await
+        .context("Failed to list filters")?;
+
+        Ok(response.filter.unwrap_or_default())
+    }
+
+    /// Creates a filter and returns its ID.
+    pub async fn create_filter(&self, filter: Filter) -> Result<String> {
+        let (_, created) = observe(
+            "settings.filters.create",
+            self.hub
+                .users()
+                .settings_filters_create(filter, "me")
+                .add_scope(SETTINGS_SCOPE)
+                .doit(),
+        )
+        .await
+        .context("Failed to create filter")?;
+
+        created.id.context("Created filter has no ID")
+    }
+
+    pub async fn delete_filter(&self, filter_id: &str) -> Result<()> {
+        observe(
+            "settings.filters.delete",
+            self.hub
+                .users()
+                .settings_filters_delete("me", filter_id)
+                .add_scope(SETTINGS_SCOPE)
+                .doit(),
+        )
+        .await
+        .context("Failed to delete filter")?;
+
+        debug!("Deleted filter {}", filter_id);
+        Ok(())
+    }
+}
+
+/// Fails unless the cached token may manage Gmail filters. Checked up front so a
+/// daemon does not start an interactive consent flow for the missing scope.
+pub fn require_settings_scope() -> Result<()> {
+    let path = get_config_path(TOKEN_CACHE_FILE);
+    let cache = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default();
+
+    let mut scopes = BTreeSet::new();
+    collect_scopes(&cache, &mut scopes);
+    if !scopes.contains(SETTINGS_SCOPE) {
+        anyhow::bail!(
+            "The Gmail authorization does not cover filters ({}); run `gmail_router auth login` again to grant it",
+            SETTINGS_SCOPE
+        );
+    }
+    Ok(())
+}
+
+fn collect_scopes(value: &serde_json::Value, scopes: &mut BTreeSet<String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    serde_json::Value::Array(items) if key == "scopes" => scopes.extend(
+                        items
+                            .iter()
+                            .filter_map(|item| item.as_str().map(String::from)),
+                    ),
+                    _ => collect_scopes(value, scopes),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                collect_scopes(item, scopes);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Revokes every refresh token in the token cache at Google and deletes the cache.
+pub async fn revoke_cached_tokens() -> Result<usize> {
+    let path = get_config_path(TOKEN_CACHE_FILE);
+    let contents = std::fs::read_to_string(&path).context("No cached token found")?;
+    let cache: serde_json::Value =
+        serde_json::from_str(&contents).context("Failed to parse token cache")?;
+
+    let mut tokens = Vec::new();
+    collect_refresh_tokens(&cache, &mut tokens);
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .context("Failed to load native roots")?
+        .https_only()
+        .enable_http1()
+        .build();
+    let client: hyper::Client<_, hyper::Body> = hyper::Client::builder().build(https);
+
+    for token in &tokens {
+        let body = format!(
+            "token={}",
+            token
+                .replace('%', "%25")
+                .replace('/', "%2F")
+                .replace('+', "%2B")
+        );
+        let request = hyper::Request::post("https://oauth2.googleapis.com/revoke")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(hyper::Body::from(body))
+            .context("Failed to build revoke request")?;
+
+        let response = client
+            .request(request)
+            .await
+            .context("Failed to revoke token")?;
+        if !response.status().is_success() {
+            warn!("Token revocation returned {}", response.status());
+        }
+    }
+
+    std::fs::remove_file(&path).context("Failed to delete token cache")?;
+    Ok(tokens.len())
+}
+
+fn collect_refresh_tokens(value: &serde_json::Value, tokens: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    serde_json::Value::String(token) if key == "refresh_token" => {
+                        tokens.push(token.clone())
+                    }
+                    _ => collect_refresh_tokens(value, tokens),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                collect_refresh_tokens(item, tokens);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_covers_messages_outside_the_inbox() {
+        let labels =
+            |list: &[&str]| -> Vec<String> { list.iter().map(|l| l.to_string()).collect() };
+        let archived = labels(&["CATEGORY_PROMOTIONS", "UNREAD"]);
+        let spam = labels(&["SPAM", "CATEGORY_PROMOTIONS"]);
+
+        let inbox = SearchConfig::default();
+        assert!(inbox.covers(&labels(&["INBOX", "UNREAD"])));
+        assert!(!inbox.covers(&archived));
+
+        let promotions = SearchConfig {
+            categories: vec!["Promotions".to_string()],
+            ..Default::default()
+        };
+        assert!(promotions.covers(&archived));
+        assert!(!promotions.covers(&spam));
+        assert!(!promotions.covers(&labels(&["INBOX"])));
+
+        let unread = SearchConfig {
+            label_ids: vec!["UNREAD".to_string()],
+            include_spam_trash: true,
+            ..Default::default()
+        };
+        assert!(unread.covers(&archived));
+        assert!(!unread.covers(&spam));
+
+        let query = SearchConfig {
+            query: Some("in:inbox OR in:spam".to_string()),
+            ..Default::default()
+        };
+        assert!(query.covers(&spam));
+    }
+
+    #[test]
+    fn test_collect_scopes() {
+        let cache = serde_json::json!([
+            {"scopes": [MAIL_SCOPE], "token": {"access_token": "a"}},
+            {"scopes": [MAIL_SCOPE, SETTINGS_SCOPE], "token": {"refresh_token": "r"}},
+        ]);
+        let mut scopes = BTreeSet::new();
+        collect_scopes(&cache, &mut scopes);
+        assert_eq!(
+            scopes.into_iter().collect::<Vec<_>>(),
+            vec![MAIL_SCOPE, SETTINGS_SCOPE]
+        );
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_after_filter() {
+        // Time of day counts, unlike the old YYYY/MM/DD filter
+        assert_eq!(after_filter(at("2024-01-01T15:30:00Z")), "after:1704122999");
+        assert_eq!(after_filter(at("2024-01-01T00:00:00Z")), "after:1704067199");
+
+        // Milliseconds are covered by the one second of overlap
+        assert_eq!(
+            after_filter(at("2024-01-01T15:30:00.999Z")),
+            after_filter(at("2024-01-01T15:30:00Z"))
+        );
+
+        // 23:30 in Los Angeles on June 11 is already June 12 in UTC; the cursor is
+        // the same instant either way and no longer depends on Gmail's timezone
+        assert_eq!(
+            after_filter(at("2024-06-11T23:30:00-07:00")),
+            after_filter(at("2024-06-12T06:30:00Z"))
+        );
+        assert_eq!(
+            after_filter(at("2024-06-12T00:30:00+02:00")),
+            "after:1718144999"
+        );
+    }
+
+    #[test]
+    fn test_search_query() {
+        let since = at("2024-06-12T00:00:00Z");
+        let after = "after:1718150399";
+
+        let scope = SearchConfig::default();
+        assert_eq!(scope.query(since), format!("in:inbox {}", after));
+
+        let scope = SearchConfig {
+            query: Some("in:inbox OR in:spam".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            scope.query(since),
+            format!(
+                "(in:inbox OR in:spam) -label:gmail_router-quarantine {}",
+                after
+            )
+        );
+
+        let scope = SearchConfig {
+            categories: vec!["Promotions".to_string(), "updates".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            scope.query(since),
+            format!(
+                "{{category:promotions category:updates}} -label:gmail_router-quarantine {}",
+                after
+            )
+        );
+
+        let scope = SearchConfig {
+            label_ids: vec!["Label_42".to_string()],
+            include_spam_trash: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            scope.query(since),
+            format!("-label:gmail_router-quarantine {}", after)
+        );
+    }
 }
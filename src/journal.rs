@@ -0,0 +1,595 @@
+use crate::audit::{AuditEntry, AuditLog, AuditResult};
+use crate::backup::{self, BackupConfig};
+use crate::config::Action;
+use crate::gmail::GmailClient;
+use crate::labels::AliasLabel;
+use crate::processor::MessageFacts;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// One mutation of a message, as written to journal.jsonl.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct JournalEntry {
+    pub at: DateTime<Utc>,
+    /// Processing cycle or command that made the change
+    pub run_id: String,
+    pub message_id: String,
+    /// `None` when alias labels were added to a kept message
+    pub action: Option<Action>,
+    /// Set on the entry recording that an earlier `action` was reverted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub undo: bool,
+    /// Recipients on the routed domain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    pub prior_labels: BTreeSet<String>,
+    /// Labels after the change; empty for permanently deleted messages
+    pub new_labels: BTreeSet<String>,
+}
+
+impl JournalEntry {
+    /// The action's name, or `label` for added alias labels.
+    pub fn change(&self) -> String {
+        self.action
+            .map_or("label".to_string(), |action| action.to_string())
+    }
+}
+
+/// Applies actions to messages, journals each change and audits every decision
+/// under one run ID.
+pub struct Recorder {
+    path: PathBuf,
+    run_id: String,
+    audit: AuditLog,
+    backup: BackupConfig,
+}
+
+impl Recorder {
+    pub fn new<P: Into<PathBuf>>(path: P, audit: AuditLog) -> Self {
+        Self {
+            path: path.into(),
+            run_id: new_run_id(Utc::now()),
+            audit,
+            backup: BackupConfig::default(),
+        }
+    }
+
+    /// Archives messages before the actions listed in `backup`.
+    pub fn with_backup(mut self, backup: BackupConfig) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    pub fn with_run_id(mut self, run_id: String) -> Self {
+        self.run_id = run_id;
+        self
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Audits a decision to leave the message alone.
+    pub fn keep(&self, message_id: &str, facts: &MessageFacts, rule: Option<&str>) {
+        self.audit
+            .record(&self.audit_entry(message_id, facts, rule, None, Ok(())));
+    }
+
+    pub async fn apply(
+        &self,
+        client: &GmailClient,
+        message_id: &str,
+        action: Action,
+        facts: &MessageFacts,
+        rule: Option<&str>,
+    ) -> Result<()> {
+        let result = match self.backup(client, message_id, action, facts).await {
+            Ok(()) => client.apply_action(message_id, action).await,
+            Err(e) => Err(e),
+        };
+        self.audit.record(&self.audit_entry(
+            message_id,
+            facts,
+            rule,
+            Some(action),
+            result.as_ref().map(|_| ()),
+        ));
+        result?;
+
+        self.journal(client, message_id, Some(action), false, facts)
+            .await;
+        Ok(())
+    }
+
+    /// Adds alias labels to a kept message. The message is audited as kept and
+    /// the change journaled, so `undo` removes the labels again.
+    pub async fn label(
+        &self,
+        client: &GmailClient,
+        message_id: &str,
+        facts: &MessageFacts,
+        rule: Option<&str>,
+        labels: &[AliasLabel],
+    ) -> Result<()> {
+        let add = labels.iter().map(|label| label.id.clone()).collect();
+        let result = client
+            .modify_labels(message_id, add, Vec::new())
+            .await
+            .context("Failed to apply alias labels");
+        let mut audit =
+            self.audit_entry(message_id, facts, rule, None, result.as_ref().map(|_| ()));
+        audit.labels = labels.iter().map(|label| label.name.clone()).collect();
+        self.audit.record(&audit);
+        result?;
+
+        self.journal(client, message_id, None, false, facts).await;
+        Ok(())
+    }
+
+    /// Returns a quarantined message to the inbox, audited and journaled as
+    /// undoing its quarantine.
+    pub async fn release(
+        &self,
+        client: &GmailClient,
+        message_id: &str,
+        facts: &MessageFacts,
+    ) -> Result<()> {
+        let result = client.release_message(message_id).await;
+        let mut audit = self.audit_entry(
+            message_id,
+            facts,
+            None,
+            Some(Action::Quarantine),
+            result.as_ref().map(|_| ()),
+        );
+        audit.undo = true;
+        self.audit.record(&audit);
+        result?;
+
+        self.journal(client, message_id, Some(Action::Quarantine), true, facts)
+            .await;
+        Ok(())
+    }
+
+    /// Journals a change made to a message; `facts` holds its labels before.
+    async fn journal(
+        &self,
+        client: &GmailClient,
+        message_id: &str,
+        action: Option<Action>,
+        undo: bool,
+        facts: &MessageFacts,
+    ) {
+        let new_labels = match action {
+            Some(Action::Delete) if !undo => BTreeSet::new(),
+            _ => client.get_labels(message_id).await.unwrap_or_else(|e| {
+                warn!("Failed to read labels of message {}: {:#}", message_id, e);
+                BTreeSet::new()
+            }),
+        };
+        let entry = JournalEntry {
+            at: Utc::now(),
+            run_id: self.run_id.clone(),
+            message_id: message_id.to_string(),
+            action,
+            undo,
+            recipients: facts.recipients.clone(),
+            prior_labels: facts.labels.iter().cloned().collect(),
+            new_labels,
+        };
+        if let Err(e) = append(&self.path, &[entry]) {
+            warn!("Failed to journal message {}: {:#}", message_id, e);
+        }
+    }
+
+    /// Stores the raw message if `action` needs a backup. A failed backup stops the action.
+    async fn backup(
+        &self,
+        client: &GmailClient,
+        message_id: &str,
+        action: Action,
+        facts: &MessageFacts,
+    ) -> Result<()> {
+        if !self.backup.applies_to(action) {
+            return Ok(());
+        }
+
+        let raw = client.get_raw_message(message_id).await?;
+        let alias = facts.recipients.first().map_or("unknown", String::as_str);
+        let path = backup::store(
+            &self.backup.root(),
+            self.backup.format,
+            alias,
+            facts.received.unwrap_or_else(Utc::now),
+            message_id,
+            &raw,
+        )
+        .context("Failed to back up message")?;
+        debug!("Backed up message {} to {:?}", message_id, path);
+        Ok(())
+    }
+
+    fn audit_entry(
+        &self,
+        message_id: &str,
+        facts: &MessageFacts,
+        rule: Option<&str>,
+        action: Option<Action>,
+        result: Result<(), &anyhow::Error>,
+    ) -> AuditEntry {
+        AuditEntry {
+            at: Utc::now(),
+            run_id: self.run_id.clone(),
+            message_id: message_id.to_string(),
+            thread_id: facts.thread_id.clone(),
+            subject: facts.subject.clone(),
+            from: facts.from.clone(),
+            recipients: facts.recipients.clone(),
+            rule: rule.map(str::to_string),
+            action,
+            undo: false,
+            labels: Vec::new(),
+            result: match (action, &result) {
+                (_, Err(_)) => AuditResult::Failed,
+                (None, Ok(())) => AuditResult::Kept,
+                (Some(_), Ok(())) => AuditResult::Applied,
+            },
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+/// Identifier for a new run, e.g. `20240612T083000-1a2b`.
+pub fn new_run_id(now: DateTime<Utc>) -> String {
+    format!(
+        "{}-{:04x}",
+        now.format("%Y%m%dT%H%M%S"),
+        now.timestamp_subsec_micros() & 0xffff
+    )
+}
+
+pub fn append<P: AsRef<Path>>(path: P, entries: &[JournalEntry]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.as_ref())
+        .context("Failed to open journal")?;
+
+    for entry in entries {
+        let line = serde_json::to_string(entry).context("Failed to serialize journal entry")?;
+        writeln!(file, "{}", line).context("Failed to write journal")?;
+    }
+
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<JournalEntry>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(path).context("Failed to read journal")?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("Failed to parse journal"))
+        .collect()
+}
+
+/// Which journal entries `undo` reverts. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct UndoFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub address: Option<String>,
+    pub run_id: Option<String>,
+}
+
+impl UndoFilter {
+    fn matches(&self, entry: &JournalEntry) -> bool {
+        self.since.is_none_or(|since| entry.at >= since)
+            && self
+                .address
+                .as_ref()
+                .is_none_or(|address| entry.recipients.contains(address))
+            && self
+                .run_id
+                .as_ref()
+                .is_none_or(|run_id| entry.run_id == *run_id)
+    }
+}
+
+/// Entries matching `filter` that have not been undone yet, newest first.
+/// Only the latest change of each message is selected, so undoing restores the
+/// labels from before the router's most recent action.
+pub fn select<'a>(entries: &'a [JournalEntry], filter: &UndoFilter) -> Vec<&'a JournalEntry> {
+    let mut handled = HashSet::new();
+    let mut selected = Vec::new();
+
+    for entry in entries.iter().rev() {
+        if !handled.insert(entry.message_id.as_str()) {
+            continue;
+        }
+        if !entry.undo && filter.matches(entry) {
+            selected.push(entry);
+        }
+    }
+
+    selected
+}
+
+/// Label changes that take a message from `current` back to `prior`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Restore {
+    pub untrash: bool,
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+pub fn restore_labels(prior: &BTreeSet<String>, current: &BTreeSet<String>) -> Restore {
+    const TRASH: &str = "TRASH";
+
+    Restore {
+        untrash: current.contains(TRASH) && !prior.contains(TRASH),
+        add: prior
+            .difference(current)
+            .filter(|l| *l != TRASH)
+            .cloned()
+            .collect(),
+        remove: current
+            .difference(prior)
+            .filter(|l| *l != TRASH)
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Reverts the selected entries and journals each revert under `run_id`.
+/// Returns the number of messages restored; if any revert failed, the others
+/// are still attempted and the failures are returned together as an error.
+pub async fn undo(
+    client: &GmailClient,
+    recorder: &Recorder,
+    entries: &[&JournalEntry],
+) -> Result<usize> {
+    undo_each(entries, async |entry| revert(client, recorder, entry).await).await
+}
+
+/// Runs `revert` on every entry. It returns whether the message was restored.
+async fn undo_each(
+    entries: &[&JournalEntry],
+    mut revert: impl AsyncFnMut(&JournalEntry) -> Result<bool>,
+) -> Result<usize> {
+    let mut restored = 0;
+    let mut failures = Vec::new();
+
+    for entry in entries {
+        match revert(entry).await {
+            Ok(true) => restored += 1,
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Cannot restore message {}: {:#}", entry.message_id, e);
+                failures.push(format!("{}: {:#}", entry.message_id, e));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "Restored {} of {} messages; failed to restore {}:\n  {}",
+            restored,
+            entries.len(),
+            failures.len(),
+            failures.join("\n  ")
+        );
+    }
+    Ok(restored)
+}
+
+/// Reverts one entry. Returns false for a message that was permanently deleted.
+async fn revert(client: &GmailClient, recorder: &Recorder, entry: &JournalEntry) -> Result<bool> {
+    if entry.action == Some(Action::Delete) {
+        warn!(
+            "Message {} was permanently deleted and cannot be restored",
+            entry.message_id
+        );
+        return Ok(false);
+    }
+
+    let current = client.get_labels(&entry.message_id).await?;
+
+    let restore = restore_labels(&entry.prior_labels, &current);
+    let result = async {
+        if restore.untrash {
+            client.untrash_message(&entry.message_id).await?;
+        }
+        if !restore.add.is_empty() || !restore.remove.is_empty() {
+            client
+                .modify_labels(&entry.message_id, restore.add, restore.remove)
+                .await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    let facts = MessageFacts {
+        recipients: entry.recipients.clone(),
+        ..Default::default()
+    };
+    let mut audit = recorder.audit_entry(
+        &entry.message_id,
+        &facts,
+        None,
+        entry.action,
+        result.as_ref().map(|_| ()),
+    );
+    audit.undo = true;
+    recorder.audit.record(&audit);
+    result?;
+
+    append(
+        &recorder.path,
+        &[JournalEntry {
+            at: Utc::now(),
+            run_id: recorder.run_id.clone(),
+            message_id: entry.message_id.clone(),
+            action: entry.action,
+            undo: true,
+            recipients: entry.recipients.clone(),
+            prior_labels: current,
+            new_labels: entry.prior_labels.clone(),
+        }],
+    )?;
+    info!("Restored message {} ({})", entry.message_id, entry.change());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(list: &[&str]) -> BTreeSet<String> {
+        list.iter().map(|l| l.to_string()).collect()
+    }
+
+    fn entry(minute: u32, run_id: &str, message_id: &str, recipient: &str) -> JournalEntry {
+        JournalEntry {
+            at: format!("2024-06-12T08:{:02}:00Z", minute).parse().unwrap(),
+            run_id: run_id.to_string(),
+            message_id: message_id.to_string(),
+            action: Some(Action::Spam),
+            undo: false,
+            recipients: vec![recipient.to_string()],
+            prior_labels: labels(&["INBOX"]),
+            new_labels: labels(&["SPAM"]),
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let undone = JournalEntry {
+            undo: true,
+            ..entry(20, "undo-1", "m3", "shop")
+        };
+        let entries = vec![
+            entry(0, "run-1", "m1", "shop"),
+            entry(1, "run-1", "m2", "news"),
+            entry(10, "run-2", "m3", "shop"),
+            undone,
+        ];
+
+        let ids = |filter: &UndoFilter| -> Vec<String> {
+            select(&entries, filter)
+                .iter()
+                .map(|e| e.message_id.clone())
+                .collect()
+        };
+
+        assert_eq!(ids(&UndoFilter::default()), vec!["m2", "m1"]);
+        assert_eq!(
+            ids(&UndoFilter {
+                address: Some("shop".to_string()),
+                ..Default::default()
+            }),
+            vec!["m1"]
+        );
+        assert_eq!(
+            ids(&UndoFilter {
+                since: Some("2024-06-12T08:01:00Z".parse().unwrap()),
+                ..Default::default()
+            }),
+            vec!["m2"]
+        );
+        assert!(ids(&UndoFilter {
+            run_id: Some("run-2".to_string()),
+            ..Default::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_label_entries() {
+        let labeled = JournalEntry {
+            action: None,
+            new_labels: labels(&["INBOX", "Label_7"]),
+            ..entry(0, "run-1", "m1", "shop")
+        };
+        let line = serde_json::to_string(&labeled).unwrap();
+        assert_eq!(
+            serde_json::from_str::<JournalEntry>(&line).unwrap(),
+            labeled
+        );
+        assert_eq!(labeled.change(), "label");
+        assert_eq!(entry(0, "run-1", "m2", "shop").change(), "spam");
+
+        let selected = select(std::slice::from_ref(&labeled), &UndoFilter::default());
+        assert_eq!(selected, vec![&labeled]);
+        let restore = restore_labels(&labeled.prior_labels, &labeled.new_labels);
+        assert_eq!(restore.remove, vec!["Label_7"]);
+        assert!(restore.add.is_empty());
+    }
+
+    #[test]
+    fn test_restore_labels() {
+        let restore = restore_labels(&labels(&["INBOX", "UNREAD"]), &labels(&["SPAM", "UNREAD"]));
+        assert_eq!(
+            restore,
+            Restore {
+                untrash: false,
+                add: vec!["INBOX".to_string()],
+                remove: vec!["SPAM".to_string()],
+            }
+        );
+
+        let restore = restore_labels(&labels(&["INBOX"]), &labels(&["INBOX", "TRASH"]));
+        assert_eq!(
+            restore,
+            Restore {
+                untrash: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_undo_continues_after_failure() {
+        let entries = [
+            entry(0, "run-1", "m1", "shop"),
+            entry(1, "run-1", "gone", "shop"),
+            entry(2, "run-1", "m3", "news"),
+        ];
+        let selected: Vec<_> = entries.iter().collect();
+
+        let mut attempted = Vec::new();
+        let result = undo_each(&selected, async |entry| {
+            attempted.push(entry.message_id.clone());
+            if entry.message_id == "gone" {
+                anyhow::bail!("Requested entity was not found");
+            }
+            Ok(true)
+        })
+        .await;
+
+        assert_eq!(attempted, vec!["m1", "gone", "m3"]);
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(
+            error.starts_with("Restored 2 of 3 messages; failed to restore 1:"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("gone: Requested entity was not found"),
+            "{}",
+            error
+        );
+
+        let result = undo_each(&selected[..1], async |_| Ok(true)).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+}
@@ -0,0 +1,273 @@
+//! Gmail labels per alias, applied to the mail the router keeps.
+//!
+//! Label IDs are kept in state.json. A label renamed in Gmail keeps being used
+//! under its new name; a label whose configured name changes is renamed rather
+//! than created again.
+
+use crate::config::RoutingConfig;
+use crate::gmail::GmailClient;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// `alias_labels` section of credentials.yaml.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AliasLabelsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Parent label, e.g. `aliases` or `Mail/aliases`; empty for top-level labels
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Label by the alias's `labels` in routing.yaml (its groups), if it has any
+    #[serde(default)]
+    pub groups: bool,
+}
+
+fn default_prefix() -> String {
+    "aliases".to_string()
+}
+
+impl Default for AliasLabelsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            prefix: default_prefix(),
+            groups: false,
+        }
+    }
+}
+
+impl AliasLabelsConfig {
+    pub fn validate(&self) -> Result<()> {
+        let prefix = self.prefix.trim_matches('/');
+        if !prefix.is_empty() && prefix.split('/').any(|part| part.trim().is_empty()) {
+            bail!("alias_labels.prefix {:?} has an empty level", self.prefix);
+        }
+        Ok(())
+    }
+
+    /// The aliases or groups to label mail to `recipients` with. Blocked
+    /// recipients get no label.
+    pub fn keys(&self, recipients: &[String], routing_config: &RoutingConfig) -> BTreeSet<String> {
+        let mut keys = BTreeSet::new();
+        for recipient in recipients {
+            if !routing_config.is_allowed(recipient) {
+                continue;
+            }
+            let groups = routing_config
+                .addresses
+                .get(recipient)
+                .map(|entry| &entry.labels)
+                .filter(|labels| self.groups && !labels.is_empty());
+            match groups {
+                Some(groups) => keys.extend(groups.iter().cloned()),
+                None => {
+                    keys.insert(recipient.clone());
+                }
+            }
+        }
+        keys
+    }
+
+    /// Full Gmail label name for an alias or group.
+    pub fn label_name(&self, key: &str) -> String {
+        match self.prefix.trim_matches('/') {
+            "" => key.to_string(),
+            prefix => format!("{}/{}", prefix, key),
+        }
+    }
+}
+
+/// A label the router created or adopted for an alias or group.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AliasLabel {
+    pub id: String,
+    /// Name of the label when the router last saw it
+    pub name: String,
+    /// Name the configuration gave the label
+    pub configured: String,
+}
+
+/// Resolves and applies alias labels during one cycle.
+pub struct AliasLabeler {
+    config: AliasLabelsConfig,
+    /// Labels by alias or group
+    cache: BTreeMap<String, AliasLabel>,
+    /// Names of the mailbox's labels by ID, listed once per cycle
+    existing: Option<HashMap<String, String>>,
+    changed: bool,
+}
+
+/// What to do with a cached label, given its current name in Gmail.
+#[derive(Debug, PartialEq)]
+enum Reconcile {
+    Use,
+    /// Renamed in Gmail; keep using it under the new name
+    Renamed(String),
+    /// The configured name changed; rename the label to it
+    Rename,
+    /// Deleted in Gmail
+    Gone,
+}
+
+fn reconcile(cached: &AliasLabel, current: Option<&str>, wanted: &str) -> Reconcile {
+    match current {
+        None => Reconcile::Gone,
+        Some(_) if cached.configured != wanted => Reconcile::Rename,
+        Some(current) if current != cached.name => Reconcile::Renamed(current.to_string()),
+        Some(_) => Reconcile::Use,
+    }
+}
+
+impl AliasLabeler {
+    pub fn new(config: AliasLabelsConfig, cache: BTreeMap<String, AliasLabel>) -> Self {
+        Self {
+            config,
+            cache,
+            existing: None,
+            changed: false,
+        }
+    }
+
+    pub fn keys(&self, recipients: &[String], routing_config: &RoutingConfig) -> BTreeSet<String> {
+        self.config.keys(recipients, routing_config)
+    }
+
+    /// The label cache to save, if it changed.
+    pub fn changed_cache(&self) -> Option<&BTreeMap<String, AliasLabel>> {
+        self.changed.then_some(&self.cache)
+    }
+
+    /// The labels for `keys` that `current` (the message's label IDs) lacks,
+    /// created in Gmail if needed.
+    pub async fn missing(
+        &mut self,
+        gmail_client: &GmailClient,
+        keys: &BTreeSet<String>,
+        current: &[String],
+    ) -> Result<Vec<AliasLabel>> {
+        let mut missing: Vec<AliasLabel> = Vec::new();
+        for key in keys {
+            let id = self.label_id(gmail_client, key).await?;
+            if !current.contains(&id) && !missing.iter().any(|label| label.id == id) {
+                missing.extend(self.cache.get(key).cloned());
+            }
+        }
+        Ok(missing)
+    }
+
+    async fn label_id(&mut self, gmail_client: &GmailClient, key: &str) -> Result<String> {
+        let wanted = self.config.label_name(key);
+
+        if let Some(cached) = self.cache.get(key).cloned() {
+            if self.existing.is_none() {
+                let labels = gmail_client.list_labels().await?;
+                self.existing = Some(
+                    labels
+                        .into_iter()
+                        .filter_map(|label| Some((label.id?, label.name?)))
+                        .collect(),
+                );
+            }
+            let current = self.existing.as_ref().and_then(|e| e.get(&cached.id));
+
+            match reconcile(&cached, current.map(String::as_str), &wanted) {
+                Reconcile::Use => return Ok(cached.id),
+                Reconcile::Renamed(name) => {
+                    self.remember(key, &cached.id, &name, &wanted);
+                    return Ok(cached.id);
+                }
+                Reconcile::Rename => {
+                    gmail_client.rename_label(&cached.id, &wanted).await?;
+                    self.remember(key, &cached.id, &wanted, &wanted);
+                    return Ok(cached.id);
+                }
+                Reconcile::Gone => {
+                    self.cache.remove(key);
+                    self.changed = true;
+                }
+            }
+        }
+
+        let id = gmail_client.ensure_label(&wanted).await?;
+        self.remember(key, &id, &wanted, &wanted);
+        Ok(id)
+    }
+
+    fn remember(&mut self, key: &str, id: &str, name: &str, configured: &str) {
+        let label = AliasLabel {
+            id: id.to_string(),
+            name: name.to_string(),
+            configured: configured.to_string(),
+        };
+        if let Some(existing) = self.existing.as_mut() {
+            existing.insert(label.id.clone(), label.name.clone());
+        }
+        self.cache.insert(key.to_string(), label);
+        self.changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_and_names() {
+        let routing: RoutingConfig = serde_yaml::from_str(
+            "addresses:\n  shop: true\n  news:\n    allowed: true\n    labels: [reading, lists]\n  spam: false\n",
+        )
+        .unwrap();
+        let recipients = ["shop", "news", "spam", "unknown"].map(String::from);
+
+        let mut config = AliasLabelsConfig::default();
+        let keys: Vec<_> = config.keys(&recipients, &routing).into_iter().collect();
+        assert_eq!(keys, vec!["news", "shop", "unknown"]);
+        assert_eq!(config.label_name("shop"), "aliases/shop");
+
+        config.groups = true;
+        config.prefix = "Mail/aliases/".to_string();
+        let keys: Vec<_> = config.keys(&recipients, &routing).into_iter().collect();
+        assert_eq!(keys, vec!["lists", "reading", "shop", "unknown"]);
+        assert_eq!(config.label_name("shop"), "Mail/aliases/shop");
+
+        config.prefix = String::new();
+        assert_eq!(config.label_name("shop"), "shop");
+        config.validate().unwrap();
+        config.prefix = "a//b".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let cached = AliasLabel {
+            id: "Label_1".to_string(),
+            name: "aliases/shop".to_string(),
+            configured: "aliases/shop".to_string(),
+        };
+
+        assert_eq!(
+            reconcile(&cached, Some("aliases/shop"), "aliases/shop"),
+            Reconcile::Use
+        );
+        assert_eq!(
+            reconcile(&cached, Some("Shops/amazon"), "aliases/shop"),
+            Reconcile::Renamed("Shops/amazon".to_string())
+        );
+        assert_eq!(
+            reconcile(&cached, Some("aliases/shop"), "mail/shop"),
+            Reconcile::Rename
+        );
+
+        let renamed = AliasLabel {
+            name: "Shops/amazon".to_string(),
+            ..cached.clone()
+        };
+        assert_eq!(
+            reconcile(&renamed, Some("Shops/amazon"), "aliases/shop"),
+            Reconcile::Use
+        );
+        assert_eq!(reconcile(&cached, None, "aliases/shop"), Reconcile::Gone);
+    }
+}
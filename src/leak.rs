@@ -0,0 +1,297 @@
+use crate::state::{AddressStats, State};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// `leak_detection` section of credentials.yaml.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeakDetectionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Messages an address must have received before its senders count as learned
+    #[serde(default = "default_min_messages")]
+    pub min_messages: u64,
+    #[serde(default)]
+    pub action: LeakAction,
+}
+
+fn default_min_messages() -> u64 {
+    5
+}
+
+impl Default for LeakDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_messages: default_min_messages(),
+            action: LeakAction::default(),
+        }
+    }
+}
+
+/// What to do with an address that looks leaked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeakAction {
+    /// Only log, notify and report
+    #[default]
+    Log,
+    /// Also block the address and route the message like other blocked mail
+    Block,
+}
+
+/// A message to an alias from a domain unrelated to its usual senders.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LeakEvent {
+    pub detected_at: DateTime<Utc>,
+    pub address: String,
+    pub sender_domain: String,
+    pub known_domains: Vec<String>,
+    pub message_id: String,
+    pub action: LeakAction,
+}
+
+/// Suffixes of two labels under which domains are registered, e.g. `shop.co.uk`.
+const MULTI_LABEL_SUFFIXES: &[&str] = &[
+    "ac.jp", "ac.nz", "ac.uk", "ac.za", "co.at", "co.id", "co.il", "co.in", "co.jp", "co.kr",
+    "co.nz", "co.th", "co.uk", "co.za", "com.ar", "com.au", "com.br", "com.cn", "com.co", "com.hk",
+    "com.mx", "com.my", "com.ng", "com.pe", "com.ph", "com.pk", "com.pl", "com.sg", "com.tr",
+    "com.tw", "com.ua", "com.vn", "edu.au", "gov.au", "gov.uk", "ltd.uk", "me.uk", "ne.jp",
+    "net.au", "net.br", "net.cn", "net.nz", "or.jp", "org.au", "org.br", "org.nz", "org.uk",
+    "org.za", "plc.uk",
+];
+
+/// The registrable part of a domain: `mail.shop.com` -> `shop.com`.
+///
+/// Without a public suffix list, only the suffixes in [`MULTI_LABEL_SUFFIXES`]
+/// keep a third label: `mail.shop.co.uk` -> `shop.co.uk`.
+pub fn base_domain(domain: &str) -> &str {
+    let suffix = match domain.rmatch_indices('.').nth(1) {
+        Some((idx, _)) => &domain[idx + 1..],
+        None => return domain,
+    };
+    let keep = if MULTI_LABEL_SUFFIXES.contains(&suffix.to_ascii_lowercase().as_str()) {
+        3
+    } else {
+        2
+    };
+
+    match domain.rmatch_indices('.').nth(keep - 1) {
+        Some((idx, _)) => &domain[idx + 1..],
+        None => domain,
+    }
+}
+
+/// Returns the learned sender domains if `sender_domain` is unrelated to all of them.
+pub fn check(stats: &AddressStats, sender_domain: &str, min_messages: u64) -> Option<Vec<String>> {
+    if stats.messages < min_messages || stats.sender_domains.is_empty() {
+        return None;
+    }
+
+    let sender_base = base_domain(sender_domain);
+    let related = stats
+        .sender_domains
+        .keys()
+        .any(|known| base_domain(known) == sender_base);
+    if related {
+        return None;
+    }
+
+    Some(
+        stats
+            .top_sender_domains()
+            .into_iter()
+            .map(|(domain, _)| domain.to_string())
+            .collect(),
+    )
+}
+
+/// Leak checks for one processing cycle. Every leaked message is returned, but
+/// each address/domain pair is reported once.
+pub struct LeakDetector {
+    config: LeakDetectionConfig,
+    flagged: HashSet<(String, String)>,
+    pub events: Vec<LeakEvent>,
+}
+
+impl LeakDetector {
+    pub fn new(config: LeakDetectionConfig) -> Self {
+        Self {
+            config,
+            flagged: HashSet::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Checks a message and returns the addresses it looks leaked for. Only the
+    /// first leak of each address/domain pair is added to `events`.
+    pub fn inspect(
+        &mut self,
+        message_id: &str,
+        recipients: &[String],
+        sender_domain: Option<&str>,
+        state: &State,
+    ) -> Vec<LeakEvent> {
+        let Some(sender_domain) = sender_domain.filter(|_| self.config.enabled) else {
+            return Vec::new();
+        };
+
+        let mut found = Vec::new();
+        for recipient in recipients {
+            let Some(stats) = state.stats(recipient) else {
+                continue;
+            };
+            let Some(known_domains) = check(stats, sender_domain, self.config.min_messages) else {
+                continue;
+            };
+
+            let event = LeakEvent {
+                detected_at: Utc::now(),
+                address: recipient.clone(),
+                sender_domain: sender_domain.to_string(),
+                known_domains,
+                message_id: message_id.to_string(),
+                action: self.config.action,
+            };
+            let key = (recipient.clone(), base_domain(sender_domain).to_string());
+            if self.flagged.insert(key) {
+                self.events.push(event.clone());
+            }
+            found.push(event);
+        }
+
+        found
+    }
+
+    pub fn action(&self) -> LeakAction {
+        self.config.action
+    }
+
+    /// Addresses to block at the end of the cycle.
+    pub fn addresses_to_block(&self) -> Vec<String> {
+        self.events
+            .iter()
+            .filter(|event| event.action == LeakAction::Block)
+            .map(|event| event.address.clone())
+            .collect()
+    }
+}
+
+pub fn append_report<P: AsRef<Path>>(path: P, events: &[LeakEvent]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.as_ref())
+        .context("Failed to open leak report")?;
+
+    for event in events {
+        let line = serde_json::to_string(event).context("Failed to serialize leak event")?;
+        writeln!(file, "{}", line).context("Failed to write leak report")?;
+    }
+
+    Ok(())
+}
+
+pub fn load_report<P: AsRef<Path>>(path: P) -> Result<Vec<LeakEvent>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(path).context("Failed to read leak report")?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("Failed to parse leak report"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Sighting;
+
+    #[test]
+    fn test_base_domain() {
+        assert_eq!(base_domain("shop.com"), "shop.com");
+        assert_eq!(base_domain("mail.shop.com"), "shop.com");
+        assert_eq!(base_domain("a.b.shop.co.uk"), "shop.co.uk");
+        assert_eq!(base_domain("localhost"), "localhost");
+        assert_eq!(base_domain("email.ups.com"), "ups.com");
+        assert_eq!(base_domain("news.bbc.com"), "bbc.com");
+        assert_eq!(base_domain("mail.shop.co.uk"), "shop.co.uk");
+        assert_eq!(base_domain("co.uk"), "co.uk");
+    }
+
+    fn learned_state() -> State {
+        let at = "2024-01-01T00:00:00Z".parse().unwrap();
+        let mut sighting = Sighting::new(at, Some("shop.com"));
+        for _ in 0..5 {
+            sighting.add(at, Some("mail.shop.com"));
+        }
+        let mut state = State::default();
+        state.record_sighting("shop", &sighting);
+        state.record_sighting("fresh", &Sighting::new(at, Some("fresh.com")));
+        state
+    }
+
+    #[test]
+    fn test_inspect_reports_unrelated_domains_once() {
+        let state = learned_state();
+        let mut detector = LeakDetector::new(LeakDetectionConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        let recipients = ["shop".to_string(), "fresh".to_string()];
+
+        assert!(detector
+            .inspect("1", &recipients, Some("news.shop.com"), &state)
+            .is_empty());
+
+        let events = detector.inspect("2", &recipients, Some("spam.example"), &state);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].address, "shop");
+        assert_eq!(events[0].known_domains, vec!["mail.shop.com", "shop.com"]);
+
+        let events = detector.inspect("3", &recipients, Some("www.spam.example"), &state);
+        assert_eq!(events.len(), 1);
+        assert_eq!(detector.events.len(), 1);
+        assert_eq!(detector.events[0].message_id, "2");
+        assert!(detector.addresses_to_block().is_empty());
+    }
+
+    #[test]
+    fn test_inspect_blocks_every_leaked_message() {
+        let state = learned_state();
+        let mut detector = LeakDetector::new(LeakDetectionConfig {
+            enabled: true,
+            action: LeakAction::Block,
+            ..Default::default()
+        });
+        let recipients = ["shop".to_string()];
+
+        for message_id in ["1", "2"] {
+            let events = detector.inspect(message_id, &recipients, Some("spam.example"), &state);
+            assert_eq!(events.len(), 1, "message {}", message_id);
+            assert_eq!(events[0].action, LeakAction::Block);
+        }
+        assert_eq!(detector.events.len(), 1);
+        assert_eq!(detector.addresses_to_block(), vec!["shop"]);
+    }
+
+    #[test]
+    fn test_disabled_detector() {
+        let state = learned_state();
+        let mut detector = LeakDetector::new(LeakDetectionConfig::default());
+        let recipients = ["shop".to_string()];
+
+        assert!(detector
+            .inspect("1", &recipients, Some("spam.example"), &state)
+            .is_empty());
+    }
+}
@@ -1,5 +1,22 @@
-// For using in test util
+// Shared by the gmail_router binary
 
+pub mod audit;
+pub mod backup;
+pub mod checkpoint;
 pub mod config;
+pub mod eml;
+pub mod filters;
 pub mod gmail;
+pub mod journal;
+pub mod labels;
+pub mod leak;
+pub mod metrics;
+pub mod migrate;
+pub mod notify;
 pub mod processor;
+pub mod quarantine;
+pub mod rule_test;
+pub mod rules;
+pub mod state;
+pub mod watcher;
+pub mod yaml_edit;
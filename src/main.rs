@@ -1,13 +1,30 @@
+mod cli;
+mod commands;
+
 use anyhow::{Context, Result};
-use chrono::Utc;
-use gmail_router::config::{get_config_path, CREDENTIALS_FILE, ROUTING_FILE};
-use gmail_router::{config, gmail, processor};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use cli::{Cli, Command, GlobalArgs};
+use gmail_router::config::{
+    get_config_path, ConfigOverrides, AUDIT_FILE, CREDENTIALS_FILE, JOURNAL_FILE, LEAK_REPORT_FILE,
+    ROUTING_FILE, STATE_FILE,
+};
+use gmail_router::state::{RunSummary, State};
+use gmail_router::watcher::{self, SharedRoutingConfig};
+use gmail_router::{
+    audit, config, filters, gmail, journal, labels, leak, metrics, migrate, notify, processor,
+    quarantine,
+};
+use std::collections::BTreeSet;
 use std::path::Path;
+use std::time::Instant;
 use tokio::time::{sleep, Duration};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -15,17 +32,58 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    info!("Starting Gmail Router");
+    if let Some(dir) = &cli.global.config_dir {
+        config::set_config_dir(dir.clone());
+    }
 
+    for file in migrate::migrate(&config::config_dir())? {
+        info!(
+            "Migrated {} to configuration version {}",
+            file,
+            config::CONFIG_VERSION
+        );
+    }
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(&cli.global).await,
+        Command::Once => once(&cli.global).await,
+        Command::Scan => scan(&cli.global).await,
+        Command::Rules(cmd) => commands::rules(cmd, cli.global.format),
+        Command::Explain { message_id, eml } => {
+            commands::explain(&cli.global, message_id.as_deref(), eml.as_deref()).await
+        }
+        Command::TestRules(args) => commands::test_rules(&cli.global, args),
+        Command::Stats => commands::stats(cli.global.format),
+        Command::Leaks { address } => commands::leaks(cli.global.format, address.as_deref()),
+        Command::Audit(args) => commands::audit(cli.global.format, args),
+        Command::Undo(args) => commands::undo(&cli.global, args).await,
+        Command::Restore { path } => commands::restore(&cli.global, &path).await,
+        Command::Quarantine(cmd) => commands::quarantine(&cli.global, cmd).await,
+        Command::Filters(cmd) => commands::filters(&cli.global, cmd).await,
+        Command::Auth(cmd) => commands::auth(&cli.global, cmd).await,
+        Command::Config(cmd) => commands::config(&cli.global, cmd),
+    }
+}
+
+pub fn load_credentials(global: &GlobalArgs) -> Result<config::CredentialsConfig> {
     let credentials_path = get_config_path(CREDENTIALS_FILE);
-    let routing_path = get_config_path(ROUTING_FILE);
 
-    let creds_config = config::CredentialsConfig::load(&credentials_path).with_context(|| {
+    let env_overrides = ConfigOverrides::from_env();
+    let flag_overrides = global.overrides();
+    config::CredentialsConfig::load_with_overrides(
+        &credentials_path,
+        &[&env_overrides, &flag_overrides],
+    )
+    .with_context(|| {
         format!(
             "Failed to load credentials config. Make sure {:?} exists",
             credentials_path
         )
-    })?;
+    })
+}
+
+async fn connect(global: &GlobalArgs) -> Result<(config::CredentialsConfig, gmail::GmailClient)> {
+    let creds_config = load_credentials(global)?;
 
     info!("Domain: {}", creds_config.domain);
     info!(
@@ -38,20 +96,51 @@ async fn main() -> Result<()> {
         .await
         .context("Failed to create Gmail client")?;
 
-    if !Path::new(&routing_path).exists() {
+    Ok((creds_config, gmail_client))
+}
+
+async fn scan_new_mail(
+    gmail_client: &gmail::GmailClient,
+    creds_config: &config::CredentialsConfig,
+    rescan: bool,
+) -> Result<()> {
+    let routing_path = get_config_path(ROUTING_FILE);
+
+    let since = if !Path::new(&routing_path).exists() {
         info!("Routing config not found. Initializing...");
-        initialize_routing_config(&gmail_client, &creds_config, &mut None).await?;
+        None
+    } else if rescan {
+        None
     } else {
-        let routing_config = config::RoutingConfig::load(routing_path)
-            .context("Failed to load routing config. Make sure routing.yaml exists")?;
-        initialize_routing_config(&gmail_client, &creds_config, &mut Some(routing_config)).await?;
-    }
+        State::load(get_config_path(STATE_FILE))?.scanned_until
+    };
+
+    initialize_routing_config(gmail_client, creds_config, since).await
+}
+
+async fn run(global: &GlobalArgs) -> Result<()> {
+    info!("Starting Gmail Router");
+
+    let (creds_config, gmail_client) = connect(global).await?;
+    metrics::start(&creds_config.metrics).await?;
+    scan_new_mail(&gmail_client, &creds_config, false).await?;
+
+    let routing_path = get_config_path(ROUTING_FILE);
+    let routing_config =
+        SharedRoutingConfig::load(&routing_path).context("Failed to load routing config")?;
+    let _watcher = watcher::watch_routing_config(routing_path, routing_config.clone())?;
 
     loop {
-        match process_emails(&gmail_client, &creds_config).await {
+        match process_emails(&gmail_client, &creds_config, &routing_config).await {
             Ok(_) => info!("Email processing completed successfully"),
             Err(e) => error!("Error processing emails: {:#}", e),
         }
+        if let Err(e) = purge_quarantine(&gmail_client, &creds_config).await {
+            error!("Error expiring quarantined mail: {:#}", e);
+        }
+        if let Err(e) = sync_filters(&gmail_client, &creds_config, &routing_config).await {
+            error!("Error syncing Gmail filters: {:#}", e);
+        }
 
         info!(
             "Waiting {} seconds before next check...",
@@ -61,128 +150,591 @@ async fn main() -> Result<()> {
     }
 }
 
+async fn once(global: &GlobalArgs) -> Result<()> {
+    let (creds_config, gmail_client) = connect(global).await?;
+    scan_new_mail(&gmail_client, &creds_config, false).await?;
+
+    let routing_config = SharedRoutingConfig::load(get_config_path(ROUTING_FILE))
+        .context("Failed to load routing config")?;
+    process_emails(&gmail_client, &creds_config, &routing_config).await?;
+    purge_quarantine(&gmail_client, &creds_config).await?;
+    sync_filters(&gmail_client, &creds_config, &routing_config).await?;
+    Ok(())
+}
+
+/// Journal and audit log writer for a new run.
+fn recorder(creds_config: &config::CredentialsConfig) -> journal::Recorder {
+    let audit = audit::AuditLog::new(get_config_path(AUDIT_FILE), creds_config.audit.clone());
+    journal::Recorder::new(get_config_path(JOURNAL_FILE), audit)
+        .with_backup(creds_config.backup.clone())
+}
+
+async fn purge_quarantine(
+    gmail_client: &gmail::GmailClient,
+    creds_config: &config::CredentialsConfig,
+) -> Result<usize> {
+    quarantine::purge_expired(
+        gmail_client,
+        &creds_config.quarantine,
+        &creds_config.domain,
+        &recorder(creds_config),
+        &get_config_path(STATE_FILE),
+    )
+    .await
+}
+
+/// Brings the exported Gmail filters in line with routing.yaml, if enabled.
+async fn sync_filters(
+    gmail_client: &gmail::GmailClient,
+    creds_config: &config::CredentialsConfig,
+    routing_config: &SharedRoutingConfig,
+) -> Result<()> {
+    if !creds_config.filters.sync {
+        return Ok(());
+    }
+    gmail::require_settings_scope()?;
+
+    let report = filters::export(
+        gmail_client,
+        &routing_config.current(),
+        &creds_config.domain,
+        &get_config_path(STATE_FILE),
+        true,
+        false,
+    )
+    .await?;
+    if !report.created.is_empty() || !report.deleted.is_empty() {
+        info!(
+            "Synced Gmail filters: {} created, {} deleted",
+            report.created.len(),
+            report.deleted.len()
+        );
+    }
+    Ok(())
+}
+
+async fn scan(global: &GlobalArgs) -> Result<()> {
+    let (creds_config, gmail_client) = connect(global).await?;
+    scan_new_mail(&gmail_client, &creds_config, true).await
+}
+
+/// Adds every address found in mail since `since` (or `start_date`) to the routing config.
 async fn initialize_routing_config(
     gmail_client: &gmail::GmailClient,
     creds_config: &config::CredentialsConfig,
-    routing_config: &mut Option<config::RoutingConfig>,
+    since: Option<DateTime<Utc>>,
 ) -> Result<()> {
     info!("Scanning emails to build address list...");
 
-    // Date format for Gmail API: YYYY/MM/DD
-    let date_filter: String;
-    if routing_config.is_none() {
-        date_filter = creds_config.start_date.format("%Y/%m/%d").to_string();
-    } else {
-        date_filter = routing_config
-            .as_ref()
-            .unwrap()
-            .updated_date
-            .format("%Y/%m/%d")
-            .to_string();
-    }
+    // Mail arriving while the scan runs is picked up by the next one
+    let started = Utc::now();
 
     let message_ids = gmail_client
-        .list_messages(&date_filter)
+        .list_messages(
+            &creds_config.search,
+            since.unwrap_or(creds_config.start_date),
+        )
         .await
         .context("Failed to list messages")?;
 
     info!("Found {} messages to scan", message_ids.len());
 
-    let addresses =
-        processor::collect_all_addresses(gmail_client, &message_ids, &creds_config.domain).await?;
+    let state_path = get_config_path(STATE_FILE);
+    let known = State::load(&state_path)?;
 
-    info!("Found {} unique addresses", addresses.len());
+    let discovery =
+        processor::collect_all_addresses(gmail_client, &message_ids, &creds_config.domain, &known)
+            .await?;
 
-    let routing_config = routing_config.get_or_insert(config::RoutingConfig::default());
-    for addr in addresses {
-        routing_config.add_address(addr);
-    }
+    info!(
+        "Found {} addresses with new messages",
+        discovery.sightings.len()
+    );
 
-    routing_config.update_date(Utc::now());
+    let routing_path = get_config_path(ROUTING_FILE);
+    let mut new_addresses = Vec::new();
+    config::RoutingConfig::update(&routing_path, |routing_config| {
+        for address in discovery.sightings.keys() {
+            if routing_config.add_address(address.clone()) {
+                new_addresses.push(address.clone());
+            }
+        }
+    })
+    .context("Failed to save routing config")?;
 
-    routing_config
-        .save(get_config_path(ROUTING_FILE))
-        .context("Failed to save routing config")?;
+    State::update(&state_path, |state| {
+        for (address, sighting) in &discovery.sightings {
+            state.record_sighting(address, sighting);
+        }
+        state.scanned_until = Some(started);
+    })
+    .context("Failed to save runtime state")?;
 
-    info!(
-        "Routing config created at {}",
-        get_config_path(ROUTING_FILE).to_str().unwrap()
-    );
+    info!("Routing config saved at {}", routing_path.display());
     info!("Please review and edit the config to block specific addresses");
 
+    // The very first scan finds every address at once; only later ones are news.
+    if since.is_some() {
+        notify_new_addresses(creds_config, &new_addresses).await;
+    }
+
     Ok(())
 }
 
-async fn process_emails(
-    gmail_client: &gmail::GmailClient,
+async fn notify_new_addresses(creds_config: &config::CredentialsConfig, addresses: &[String]) {
+    if !creds_config.notify.new_address {
+        return;
+    }
+
+    for address in addresses {
+        notify::send(
+            &creds_config.notify,
+            notify::NEW_ADDRESS_EVENT,
+            &format!(
+                "New address {}@{} received mail for the first time",
+                address, creds_config.domain
+            ),
+        )
+        .await;
+    }
+}
+
+/// State collected while processing the messages of one cycle.
+struct Cycle {
+    /// Runtime state as of the start of the cycle
+    state: State,
+    discovery: processor::Discovery,
+    leaks: leak::LeakDetector,
+    journal: journal::Recorder,
+    summary: RunSummary,
+    history_id: Option<u64>,
+    labeler: Option<labels::AliasLabeler>,
+    /// ID of the quarantine label, which thread mode leaves alone
+    quarantine_label: Option<String>,
+}
+
+impl Cycle {
+    fn new(creds_config: &config::CredentialsConfig, state: State, summary: RunSummary) -> Self {
+        let labeler = creds_config.alias_labels.enabled.then(|| {
+            labels::AliasLabeler::new(
+                creds_config.alias_labels.clone(),
+                state.alias_labels.clone(),
+            )
+        });
+        Self {
+            state,
+            labeler,
+            discovery: processor::Discovery::default(),
+            leaks: leak::LeakDetector::new(creds_config.leak_detection.clone()),
+            journal: recorder(creds_config).with_run_id(summary.run_id.clone()),
+            summary,
+            history_id: None,
+            quarantine_label: None,
+        }
+    }
+}
+
+/// Adds the addresses a cycle discovered to routing.yaml and reports possible leaks.
+async fn finish_cycle(
     creds_config: &config::CredentialsConfig,
+    routing: &SharedRoutingConfig,
+    cycle: &Cycle,
 ) -> Result<()> {
-    info!("Starting email processing cycle");
+    let to_block = cycle.leaks.addresses_to_block();
+
+    if !cycle.leaks.events.is_empty() {
+        leak::append_report(get_config_path(LEAK_REPORT_FILE), &cycle.leaks.events)?;
+        for event in &cycle.leaks.events {
+            notify::send(
+                &creds_config.notify,
+                notify::POSSIBLE_LEAK_EVENT,
+                &format!(
+                    "Possible leak: {}@{} received mail from {} (usually from {})",
+                    event.address,
+                    creds_config.domain,
+                    event.sender_domain,
+                    event.known_domains.join(", ")
+                ),
+            )
+            .await;
+        }
+    }
 
-    let routing_config = config::RoutingConfig::load(get_config_path(ROUTING_FILE))
-        .context("Failed to load routing config")?;
+    if cycle.discovery.is_empty() && to_block.is_empty() {
+        return Ok(());
+    }
 
-    let date_filter = routing_config.updated_date.format("%Y/%m/%d").to_string();
+    let mut new_addresses = Vec::new();
+    let updated = config::RoutingConfig::update(get_config_path(ROUTING_FILE), |routing_config| {
+        for address in cycle.discovery.sightings.keys() {
+            if routing_config.add_address(address.clone()) {
+                new_addresses.push(address.clone());
+            }
+        }
+        for address in &to_block {
+            routing_config.set_allowed(address, false);
+        }
+    })
+    .context("Failed to save discovered addresses")?;
+    routing.replace(updated);
 
-    let message_ids = gmail_client
-        .list_messages(&date_filter)
-        .await
-        .context("Failed to list messages")?;
+    if !new_addresses.is_empty() {
+        info!("Discovered {} new addresses", new_addresses.len());
+    }
+    if !to_block.is_empty() {
+        info!("Blocked possibly leaked addresses: {:?}", to_block);
+    }
+    notify_new_addresses(creds_config, &new_addresses).await;
+
+    Ok(())
+}
+
+async fn process_emails(
+    gmail_client: &gmail::GmailClient,
+    creds_config: &config::CredentialsConfig,
+    routing: &SharedRoutingConfig,
+) -> Result<()> {
+    let started = Utc::now();
+    let summary = RunSummary::new(journal::new_run_id(started), started);
+    let run_id = summary.run_id.clone();
+    let timer = Instant::now();
+
+    let result = process_cycle(gmail_client, creds_config, routing, summary).await;
+    {
+        let mut registry = metrics::registry();
+        registry.record_cycle(timer.elapsed(), result.is_ok().then(Utc::now));
+        registry.set_known_addresses(routing.current().addresses.len());
+    }
+    if let Err(e) = &result {
+        let failed = RunSummary {
+            finished: Utc::now(),
+            error: Some(format!("{:#}", e)),
+            ..RunSummary::new(run_id, started)
+        };
+        if let Err(e) = State::update(get_config_path(STATE_FILE), |state| {
+            state.record_run(failed)
+        }) {
+            warn!("Failed to record the failed run: {:#}", e);
+        }
+    }
+    result
+}
 
-    info!("Found {} messages to process", message_ids.len());
+async fn process_cycle(
+    gmail_client: &gmail::GmailClient,
+    creds_config: &config::CredentialsConfig,
+    routing: &SharedRoutingConfig,
+    summary: RunSummary,
+) -> Result<()> {
+    info!("Starting email processing cycle");
 
-    let mut deleted_count = 0;
-    let mut processed_count = 0;
+    let state_path = get_config_path(STATE_FILE);
+    let state = State::load(&state_path)?;
+    let mut checkpoint = state.checkpoint.clone();
+    let started = summary.started;
+    let since = checkpoint
+        .processed_until
+        .or(state.scanned_until)
+        .unwrap_or(creds_config.start_date);
+
+    let mut ids = if creds_config.thread_mode {
+        gmail_client.list_threads(&creds_config.search, since).await
+    } else {
+        gmail_client
+            .list_messages(&creds_config.search, since)
+            .await
+    }
+    .context("Failed to list messages")?;
 
-    for (idx, msg_id) in message_ids.iter().enumerate() {
+    let unit = if creds_config.thread_mode {
+        "threads"
+    } else {
+        "messages"
+    };
+    let retries = checkpoint.retries(&ids);
+    if !retries.is_empty() {
+        info!("Retrying {} failed {}", retries.len(), unit);
+    }
+    ids.extend(retries);
+    info!("Found {} {} to process", ids.len(), unit);
+
+    let mut cycle = Cycle::new(creds_config, state, summary);
+    if creds_config.thread_mode {
+        cycle.quarantine_label = gmail_client
+            .find_label(quarantine::QUARANTINE_LABEL)
+            .await?;
+    }
+    debug!("Run {}", cycle.journal.run_id());
+
+    for (idx, id) in ids.iter().enumerate() {
         if idx % 50 == 0 && idx > 0 {
-            info!("Progress: {}/{} messages processed", idx, message_ids.len());
+            info!("Progress: {}/{} {} processed", idx, ids.len(), unit);
         }
 
-        match process_single_message(gmail_client, msg_id, &creds_config.domain, &routing_config)
+        let routing_config = routing.current();
+        let result = if creds_config.thread_mode {
+            // A thread that failed before is evaluated in full again
+            let handled_before = (!checkpoint.failed.contains_key(id)).then_some(since);
+            process_thread(
+                gmail_client,
+                id,
+                handled_before,
+                &creds_config.search,
+                &creds_config.domain,
+                &routing_config,
+                &mut cycle,
+            )
             .await
-        {
-            Ok(deleted) => {
-                processed_count += 1;
-                if deleted {
-                    deleted_count += 1;
+        } else {
+            process_single_message(
+                gmail_client,
+                id,
+                &creds_config.domain,
+                &routing_config,
+                &mut cycle,
+            )
+            .await
+        };
+
+        match result {
+            Ok(routed) => {
+                cycle.summary.processed += 1;
+                if routed {
+                    cycle.summary.routed += 1;
                 }
+                checkpoint.record_success(id);
             }
             Err(e) => {
-                warn!("Failed to process message {}: {:#}", msg_id, e);
+                warn!("Failed to process {} {}: {:#}", unit, id, e);
+                cycle.summary.failed += 1;
+                if !checkpoint.record_failure(id, &e, Utc::now()) {
+                    error!("Giving up on {} {} after repeated failures", unit, id);
+                }
             }
         }
     }
 
     info!(
-        "Processing complete: {} processed, {} deleted",
-        processed_count, deleted_count
+        "Processing complete: {} {} processed, {} routed, {} waiting for retry",
+        cycle.summary.processed,
+        unit,
+        cycle.summary.routed,
+        checkpoint.failed.len()
     );
 
+    finish_cycle(creds_config, routing, &cycle).await?;
+
+    let Cycle {
+        discovery,
+        mut summary,
+        history_id,
+        labeler,
+        ..
+    } = cycle;
+    summary.finished = Utc::now();
+    State::update(&state_path, |state| {
+        if let Some(cache) = labeler.as_ref().and_then(|l| l.changed_cache()) {
+            state.alias_labels = cache.clone();
+        }
+        for (address, sighting) in &discovery.sightings {
+            state.record_sighting(address, sighting);
+        }
+        // Only a completed cycle moves the cursor; failed messages are retried by ID
+        state.checkpoint = checkpoint;
+        state.checkpoint.processed_until = Some(started);
+        state.record_history_id(history_id);
+        state.record_run(summary);
+    })
+    .context("Failed to save runtime state")?;
+
     Ok(())
 }
 
+/// Routing outcome of one message.
+struct Verdict {
+    message_id: String,
+    facts: processor::MessageFacts,
+    /// Decision including leak blocking
+    decision: processor::Decision,
+    rule: Option<String>,
+    /// Aliases or groups to label the message with if it is kept
+    alias_labels: BTreeSet<String>,
+}
+
+/// Records the message for discovery and leak detection and decides its fate.
+/// A message `seen` in an earlier cycle is only routed again.
+fn evaluate_message(
+    message: &google_gmail1::api::Message,
+    domain: &str,
+    routing_config: &config::RoutingConfig,
+    cycle: &mut Cycle,
+    seen: bool,
+) -> Result<Verdict> {
+    let message_id = message.id.clone().unwrap_or_default();
+    let facts = processor::MessageFacts::from_message(message, domain)?;
+    let recipients = &facts.recipients;
+    cycle.history_id = cycle.history_id.max(message.history_id);
+
+    let mut leak_action = None;
+    if !seen {
+        metrics::registry().record_processed();
+    }
+    if !seen && !recipients.is_empty() {
+        let received = processor::message_date(message).unwrap_or_else(Utc::now);
+        let sender_domain = facts.sender_domain();
+        cycle
+            .discovery
+            .observe(recipients, received, sender_domain, &cycle.state);
+
+        let leaks = cycle
+            .leaks
+            .inspect(&message_id, recipients, sender_domain, &cycle.state);
+        let protected = recipients.iter().any(|r| routing_config.is_protected(r));
+        if let (Some(event), leak::LeakAction::Block, false) =
+            (leaks.first(), cycle.leaks.action(), protected)
+        {
+            leak_action = Some(routing_config.action_for(&event.address));
+        }
+    }
+
+    let mut decision = processor::route(&facts, routing_config);
+    let rule = decision
+        .rule
+        .map(|index| routing_config.rules[index].label(index));
+    if let Some(rule) = &rule {
+        debug!("Message {} matched {}", message_id, rule);
+    }
+
+    // An explicit allow rule also overrides leak blocking
+    if decision.rule.is_none() {
+        decision.action = decision.action.or(leak_action);
+    }
+
+    let alias_labels = cycle
+        .labeler
+        .as_ref()
+        .map(|labeler| labeler.keys(recipients, routing_config))
+        .unwrap_or_default();
+
+    Ok(Verdict {
+        message_id,
+        facts,
+        decision,
+        rule,
+        alias_labels,
+    })
+}
+
+/// Applies `action` to the message, or records that it was kept.
+async fn apply_verdict(
+    gmail_client: &gmail::GmailClient,
+    verdict: &Verdict,
+    action: Option<config::Action>,
+    cycle: &mut Cycle,
+) -> Result<bool> {
+    let Verdict {
+        message_id,
+        facts,
+        rule,
+        alias_labels,
+        ..
+    } = verdict;
+
+    if let Some(action) = action {
+        info!(
+            "Applying {} to message {} (recipients: {:?}, from: {})",
+            action,
+            message_id,
+            facts.recipients,
+            facts.from.as_deref().unwrap_or("unknown")
+        );
+        cycle
+            .journal
+            .apply(gmail_client, message_id, action, facts, rule.as_deref())
+            .await?;
+        cycle.summary.count_action(action);
+        let mut registry = metrics::registry();
+        for recipient in &facts.recipients {
+            registry.record_action(action, recipient);
+        }
+        return Ok(true);
+    }
+
+    let mut labels = Vec::new();
+    if let Some(labeler) = cycle.labeler.as_mut().filter(|_| !alias_labels.is_empty()) {
+        labels = labeler
+            .missing(gmail_client, alias_labels, &facts.labels)
+            .await?;
+    }
+    if labels.is_empty() {
+        cycle.journal.keep(message_id, facts, rule.as_deref());
+    } else {
+        cycle
+            .journal
+            .label(gmail_client, message_id, facts, rule.as_deref(), &labels)
+            .await?;
+    }
+    Ok(false)
+}
+
 async fn process_single_message(
     gmail_client: &gmail::GmailClient,
     message_id: &str,
     domain: &str,
     routing_config: &config::RoutingConfig,
+    cycle: &mut Cycle,
 ) -> Result<bool> {
     let message = gmail_client.get_message(message_id).await?;
-    let recipients = processor::extract_recipients(&message, domain)?;
+    let verdict = evaluate_message(&message, domain, routing_config, cycle, false)?;
+    apply_verdict(gmail_client, &verdict, verdict.decision.action, cycle).await
+}
 
-    if recipients.is_empty() {
+/// Routes every message of a thread that is in the search scope the same way.
+/// Threads the user has replied to are left alone. Messages received before `handled_before` were
+/// evaluated in an earlier cycle; they count towards the thread's action but are
+/// not recorded again.
+async fn process_thread(
+    gmail_client: &gmail::GmailClient,
+    thread_id: &str,
+    handled_before: Option<DateTime<Utc>>,
+    scope: &gmail::SearchConfig,
+    domain: &str,
+    routing_config: &config::RoutingConfig,
+    cycle: &mut Cycle,
+) -> Result<bool> {
+    let messages = gmail_client.get_thread(thread_id).await?;
+    if messages.iter().any(processor::is_sent) {
+        debug!("Skipping thread {} with a sent message", thread_id);
         return Ok(false);
     }
 
-    if processor::should_delete_message(&recipients, routing_config) {
-        info!(
-            "Deleting message {} (recipients: {:?})",
-            message_id, recipients
-        );
-        gmail_client.delete_message(message_id).await?;
-        return Ok(true);
+    let mut verdicts = Vec::new();
+    for message in &messages {
+        let seen = handled_before
+            .zip(processor::message_date(message))
+            .is_some_and(|(cursor, received)| received < cursor);
+        let verdict = evaluate_message(message, domain, routing_config, cycle, seen)?;
+        verdicts.push((verdict, seen));
     }
 
-    Ok(false)
+    let decisions: Vec<_> = verdicts.iter().map(|(v, _)| v.decision.clone()).collect();
+    let action = processor::thread_action(&decisions);
+
+    // Messages kept in an earlier cycle were already audited
+    let mut routed = false;
+    let in_scope = |labels: &[String]| {
+        scope.covers(labels)
+            && !labels
+                .iter()
+                .any(|l| Some(l) == cycle.quarantine_label.as_ref())
+    };
+    let routable: Vec<_> = verdicts
+        .iter()
+        .filter(|(v, seen)| in_scope(&v.facts.labels) && (!seen || action.is_some()))
+        .collect();
+    for (verdict, _) in routable {
+        routed |= apply_verdict(gmail_client, verdict, action, cycle).await?;
+    }
+
+    Ok(routed)
 }
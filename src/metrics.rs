@@ -0,0 +1,434 @@
+//! Prometheus metrics, served as text on `/metrics`.
+//!
+//! Metrics are kept in one process-wide registry so the Gmail client and the
+//! processing loop can record them without threading a handle through.
+
+use crate::config::Action;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// `metrics` section of credentials.yaml.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Address the `/metrics` endpoint listens on
+    #[serde(default = "default_listen")]
+    pub listen: String,
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:9898".to_string()
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: default_listen(),
+        }
+    }
+}
+
+const CYCLE_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+const API_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Quota units Gmail charges per call, from the API's usage limits page.
+fn quota_units(endpoint: &str) -> u64 {
+    match endpoint {
+        "messages.insert" => 25,
+        "messages.delete" | "threads.list" | "threads.get" => 10,
+        "labels.list" | "settings.filters.list" => 1,
+        _ => 5,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Registry {
+    processed: u64,
+    /// By action and alias
+    actions: BTreeMap<(String, String), u64>,
+    /// By endpoint and HTTP status
+    api_calls: BTreeMap<(String, String), u64>,
+    quota_units: u64,
+    api_latency: BTreeMap<String, Histogram>,
+    cycle_duration: Histogram,
+    last_success: Option<DateTime<Utc>>,
+    known_addresses: Option<usize>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            processed: 0,
+            actions: BTreeMap::new(),
+            api_calls: BTreeMap::new(),
+            quota_units: 0,
+            api_latency: BTreeMap::new(),
+            cycle_duration: Histogram::new(CYCLE_BUCKETS),
+            last_success: None,
+            known_addresses: None,
+        }
+    }
+}
+
+impl Registry {
+    pub fn record_api_call(&mut self, endpoint: &str, status: &str, elapsed: Duration) {
+        *self
+            .api_calls
+            .entry((endpoint.to_string(), status.to_string()))
+            .or_default() += 1;
+        self.quota_units += quota_units(endpoint);
+        self.api_latency
+            .entry(endpoint.to_string())
+            .or_insert_with(|| Histogram::new(API_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_processed(&mut self) {
+        self.processed += 1;
+    }
+
+    pub fn record_action(&mut self, action: Action, alias: &str) {
+        *self
+            .actions
+            .entry((action.to_string(), alias.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Records a finished cycle; `succeeded_at` is set if it completed.
+    pub fn record_cycle(&mut self, elapsed: Duration, succeeded_at: Option<DateTime<Utc>>) {
+        self.cycle_duration.observe(elapsed.as_secs_f64());
+        if succeeded_at.is_some() {
+            self.last_success = succeeded_at;
+        }
+    }
+
+    pub fn set_known_addresses(&mut self, count: usize) {
+        self.known_addresses = Some(count);
+    }
+
+    /// The Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "gmail_router_messages_processed_total",
+            "counter",
+            "Messages processed",
+        );
+        let _ = writeln!(
+            out,
+            "gmail_router_messages_processed_total {}",
+            self.processed
+        );
+
+        header(
+            &mut out,
+            "gmail_router_actions_total",
+            "counter",
+            "Actions applied, by action and recipient alias",
+        );
+        for ((action, alias), count) in &self.actions {
+            let _ = writeln!(
+                out,
+                "gmail_router_actions_total{{action=\"{}\",alias=\"{}\"}} {}",
+                escape(action),
+                escape(alias),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "gmail_router_api_calls_total",
+            "counter",
+            "Gmail API calls, by endpoint and HTTP status",
+        );
+        for ((endpoint, status), count) in &self.api_calls {
+            let _ = writeln!(
+                out,
+                "gmail_router_api_calls_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                escape(endpoint),
+                escape(status),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "gmail_router_api_errors_total",
+            "counter",
+            "Failed Gmail API calls, by endpoint and HTTP status",
+        );
+        for ((endpoint, status), count) in &self.api_calls {
+            if status != "200" {
+                let _ = writeln!(
+                    out,
+                    "gmail_router_api_errors_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                    escape(endpoint),
+                    escape(status),
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "gmail_router_quota_units_total",
+            "counter",
+            "Estimated Gmail API quota units used",
+        );
+        let _ = writeln!(out, "gmail_router_quota_units_total {}", self.quota_units);
+
+        header(
+            &mut out,
+            "gmail_router_api_call_duration_seconds",
+            "histogram",
+            "Latency of Gmail API calls",
+        );
+        for (endpoint, histogram) in &self.api_latency {
+            histogram.render(
+                &mut out,
+                "gmail_router_api_call_duration_seconds",
+                &format!("endpoint=\"{}\"", escape(endpoint)),
+            );
+        }
+
+        header(
+            &mut out,
+            "gmail_router_cycle_duration_seconds",
+            "histogram",
+            "Duration of processing cycles",
+        );
+        self.cycle_duration
+            .render(&mut out, "gmail_router_cycle_duration_seconds", "");
+
+        if let Some(at) = self.last_success {
+            header(
+                &mut out,
+                "gmail_router_last_successful_cycle_timestamp_seconds",
+                "gauge",
+                "End of the last completed processing cycle",
+            );
+            let _ = writeln!(
+                out,
+                "gmail_router_last_successful_cycle_timestamp_seconds {}",
+                at.timestamp()
+            );
+        }
+        if let Some(count) = self.known_addresses {
+            header(
+                &mut out,
+                "gmail_router_known_addresses",
+                "gauge",
+                "Addresses in routing.yaml",
+            );
+            let _ = writeln!(out, "gmail_router_known_addresses {}", count);
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The process-wide registry.
+pub fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts serving `/metrics` in the background if enabled.
+pub async fn start(config: &MetricsConfig) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let listener = TcpListener::bind(&config.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", config.listen))?;
+    info!("Serving metrics on http://{}/metrics", config.listen);
+    tokio::spawn(serve(listener));
+    Ok(())
+}
+
+async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream).await {
+                        debug!("Metrics request failed: {:#}", e);
+                    }
+                });
+            }
+            Err(e) => debug!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let response = respond(request.lines().next().unwrap_or_default());
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The HTTP response to a request line such as `GET /metrics HTTP/1.1`.
+fn respond(request_line: &str) -> String {
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", registry().render())
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut registry = Registry::default();
+        registry.record_processed();
+        registry.record_action(Action::Spam, "shop");
+        registry.record_api_call("messages.get", "200", Duration::from_millis(80));
+        registry.record_api_call("messages.delete", "429", Duration::from_secs(3));
+        registry.record_cycle(Duration::from_secs(12), Some(DateTime::UNIX_EPOCH));
+        registry.set_known_addresses(7);
+
+        let text = registry.render();
+        for line in [
+            "gmail_router_messages_processed_total 1",
+            "gmail_router_actions_total{action=\"spam\",alias=\"shop\"} 1",
+            "gmail_router_api_calls_total{endpoint=\"messages.get\",status=\"200\"} 1",
+            "gmail_router_api_errors_total{endpoint=\"messages.delete\",status=\"429\"} 1",
+            "gmail_router_quota_units_total 15",
+            "gmail_router_api_call_duration_seconds_bucket{endpoint=\"messages.get\",le=\"0.1\"} 1",
+            "gmail_router_api_call_duration_seconds_bucket{endpoint=\"messages.delete\",le=\"2.5\"} 0",
+            "gmail_router_api_call_duration_seconds_count{endpoint=\"messages.delete\"} 1",
+            "gmail_router_cycle_duration_seconds_bucket{le=\"10\"} 0",
+            "gmail_router_cycle_duration_seconds_bucket{le=\"30\"} 1",
+            "gmail_router_cycle_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "gmail_router_cycle_duration_seconds_sum 12",
+            "gmail_router_last_successful_cycle_timestamp_seconds 0",
+            "gmail_router_known_addresses 7",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        assert!(!text.contains("api_errors_total{endpoint=\"messages.get\""));
+    }
+
+    #[test]
+    fn test_respond() {
+        let ok = respond("GET /metrics HTTP/1.1");
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.contains("# TYPE gmail_router_messages_processed_total counter"));
+        assert!(respond("GET / HTTP/1.1").starts_with("HTTP/1.1 404"));
+        assert!(respond("POST /metrics HTTP/1.1").starts_with("HTTP/1.1 405"));
+    }
+}
@@ -0,0 +1,145 @@
+//! Upgrades configuration files written by older versions of the router.
+//!
+//! Version 0 files have no `version` key. Version 1 moved the runtime state
+//! (scan time, address statistics and the processing checkpoint) out of
+//! routing.yaml and checkpoint.json into state.json.
+
+use crate::config::{
+    check_version, write_atomic, ConfigLock, CHECKPOINT_FILE, CONFIG_VERSION, CREDENTIALS_FILE,
+    ROUTING_FILE, STATE_FILE,
+};
+use crate::state;
+use crate::yaml_edit::YamlDocument;
+use anyhow::{Context, Result};
+use serde_yaml::Value;
+use std::fs;
+use std::path::Path;
+
+/// Brings the files in `dir` up to [`CONFIG_VERSION`]. Returns the names of the
+/// files that were changed.
+pub fn migrate(dir: &Path) -> Result<Vec<&'static str>> {
+    fs::create_dir_all(dir).context("Failed to create config dir")?;
+    let mut changed = Vec::new();
+
+    let moved = state::migrate(
+        &dir.join(ROUTING_FILE),
+        &dir.join(CHECKPOINT_FILE),
+        &dir.join(STATE_FILE),
+    )
+    .context("Failed to move runtime state out of routing.yaml")?;
+    if moved {
+        changed.push(STATE_FILE);
+    }
+
+    for file in [CREDENTIALS_FILE, ROUTING_FILE] {
+        let path = dir.join(file);
+        if path.exists()
+            && stamp_version(&path).with_context(|| format!("Failed to migrate {}", file))?
+        {
+            changed.push(file);
+        }
+    }
+
+    Ok(changed)
+}
+
+/// The `version` key of a YAML config file, if it has one.
+pub fn file_version(contents: &str) -> Result<Option<u32>> {
+    let doc: Value = serde_yaml::from_str(contents).context("Failed to parse YAML")?;
+    match doc.get("version") {
+        None | Some(Value::Null) => Ok(None),
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .map(Some)
+            .with_context(|| format!("version must be a whole number, got {:?}", version)),
+    }
+}
+
+/// Writes the current version into a file that predates it; returns false if
+/// the file is already current.
+fn stamp_version(path: &Path) -> Result<bool> {
+    let _lock = ConfigLock::acquire(path)?;
+    let contents = fs::read_to_string(path).context("Failed to read config file")?;
+    let version = file_version(&contents)?;
+    check_version(version.unwrap_or(0))?;
+    if version == Some(CONFIG_VERSION) {
+        return Ok(false);
+    }
+
+    let mut doc = YamlDocument::parse(&contents);
+    let value = Value::Number(CONFIG_VERSION.into());
+    match version {
+        Some(_) => doc.set_top_level("version", &value)?,
+        None => doc.prepend_top_level("version", &value)?,
+    }
+    write_atomic(path, &doc.to_string()).context("Failed to write config file")?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CredentialsConfig, RoutingConfig};
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "gmail_router_test_migrate_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_migrate_v0_files() {
+        let dir = temp_dir("v0");
+        fs::write(
+            dir.join(CREDENTIALS_FILE),
+            "# Gmail Router\ngoogle_credentials_path: secret.json\ndomain: example.com\ncheck_interval_seconds: 3600\nstart_date: 2024-01-01T00:00:00Z\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(ROUTING_FILE),
+            "addresses:\n  shop:\n    allowed: false\n    messages: 3\nupdated_date: 2024-02-01T00:00:00Z\n",
+        )
+        .unwrap();
+
+        let changed = migrate(&dir).unwrap();
+        let again = migrate(&dir).unwrap();
+        let credentials = fs::read_to_string(dir.join(CREDENTIALS_FILE)).unwrap();
+        let routing = RoutingConfig::load(dir.join(ROUTING_FILE));
+        let loaded = CredentialsConfig::load(dir.join(CREDENTIALS_FILE));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(changed, vec![STATE_FILE, CREDENTIALS_FILE, ROUTING_FILE]);
+        assert!(again.is_empty());
+        assert!(credentials.starts_with("# Gmail Router\nversion: 1\ngoogle_credentials_path"));
+        assert_eq!(loaded.unwrap().version, CONFIG_VERSION);
+        let routing = routing.unwrap();
+        assert_eq!(routing.version, CONFIG_VERSION);
+        assert!(!routing.is_allowed("shop"));
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let dir = temp_dir("newer");
+        fs::write(dir.join(ROUTING_FILE), "version: 99\naddresses: {}\n").unwrap();
+
+        let result = migrate(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("version 99 is newer"), "{}", error);
+    }
+
+    #[test]
+    fn test_file_version() {
+        assert_eq!(file_version("addresses: {}\n").unwrap(), None);
+        assert_eq!(file_version("version: 1\n").unwrap(), Some(1));
+        assert!(file_version("version: one\n").is_err());
+    }
+}
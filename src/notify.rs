@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// `notify` section of credentials.yaml.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Notify when mail arrives for an address that was never seen before
+    #[serde(default)]
+    pub new_address: bool,
+    /// Shell command run for every notification. The message is passed on stdin,
+    /// the event name in GMAIL_ROUTER_EVENT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+pub const NEW_ADDRESS_EVENT: &str = "new_address";
+pub const POSSIBLE_LEAK_EVENT: &str = "possible_leak";
+
+/// How long a notification command may run before it is killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Logs the notification and runs the configured command, if any.
+/// Failures are logged rather than returned so they never stop processing.
+pub async fn send(config: &NotifyConfig, event: &str, message: &str) {
+    warn!("[{}] {}", event, message);
+
+    if let Some(command) = &config.command {
+        if let Err(e) = run_command(command, event, message, COMMAND_TIMEOUT).await {
+            warn!("Notification command failed: {:#}", e);
+        }
+    }
+}
+
+/// Runs `command` with `message` on stdin. Only the exit status counts: a
+/// command that exits without reading stdin has still succeeded.
+async fn run_command(command: &str, event: &str, message: &str, limit: Duration) -> Result<()> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("GMAIL_ROUTER_EVENT", event)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start notification command")?;
+
+    let stdin = child.stdin.take();
+    let finished = tokio::time::timeout(limit, async {
+        if let Some(mut stdin) = stdin {
+            match stdin.write_all(message.as_bytes()).await {
+                Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+                    return Err(e).context("Failed to pass the notification to the command")
+                }
+                _ => {}
+            }
+        }
+        child
+            .wait()
+            .await
+            .context("Failed to wait for notification command")
+    })
+    .await;
+
+    let status = match finished {
+        Ok(status) => status?,
+        Err(_) => {
+            let _ = child.kill().await;
+            anyhow::bail!("Notification command timed out after {:?}", limit);
+        }
+    };
+    if !status.success() {
+        anyhow::bail!("Notification command exited with {}", status);
+    }
+
+    debug!("Notification sent: {}", event);
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let limit = Duration::from_secs(10);
+        let long = "x".repeat(1 << 20);
+
+        run_command("true", "test", &long, limit).await.unwrap();
+        run_command("cat > /dev/null", "test", "hello", limit)
+            .await
+            .unwrap();
+        assert!(run_command("exit 3", "test", "hello", limit).await.is_err());
+
+        let error = run_command("sleep 10", "test", "", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+    }
+}
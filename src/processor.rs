@@ -1,27 +1,223 @@
+use crate::config::{Action, ConflictPolicy, RoutingConfig};
+use crate::state::{Sighting, State};
 use anyhow::{Context, Result};
-use google_gmail1::api::Message;
-use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use google_gmail1::api::{Message, MessagePart};
+use std::collections::BTreeMap;
 use tracing::debug;
 
-pub fn extract_recipients(message: &Message, domain: &str) -> Result<Vec<String>> {
+/// Headers that can carry an address on the routed domain.
+const RECIPIENT_HEADERS: [&str; 5] = ["to", "cc", "bcc", "delivered-to", "x-original-to"];
+
+/// A recipient on the routed domain and the header it was found in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipientSource {
+    pub local_part: String,
+    /// Header name as written in the message
+    pub header: String,
+}
+
+impl RecipientSource {
+    /// Only `To` recipients take part in routing.
+    pub fn is_routed(&self) -> bool {
+        self.header.eq_ignore_ascii_case("to")
+    }
+}
+
+/// Every recipient on `domain` in the message's recipient headers, in header order.
+pub fn recipient_sources(message: &Message, domain: &str) -> Result<Vec<RecipientSource>> {
     let headers = message
         .payload
         .as_ref()
         .and_then(|p| p.headers.as_ref())
         .context("Message has no headers")?;
 
-    let mut recipients = Vec::new();
+    let mut sources = Vec::new();
 
     for header in headers {
         if let (Some(name), Some(value)) = (&header.name, &header.value) {
-            if name.to_lowercase() == "to" {
-                let addrs = parse_email_addresses(value, domain);
-                recipients.extend(addrs);
+            if RECIPIENT_HEADERS.contains(&name.to_lowercase().as_str()) {
+                sources.extend(parse_email_addresses(value, domain).into_iter().map(
+                    |local_part| RecipientSource {
+                        local_part,
+                        header: name.clone(),
+                    },
+                ));
+            }
+        }
+    }
+
+    Ok(sources)
+}
+
+pub fn extract_recipients(message: &Message, domain: &str) -> Result<Vec<String>> {
+    Ok(recipient_sources(message, domain)?
+        .into_iter()
+        .filter(RecipientSource::is_routed)
+        .map(|source| source.local_part)
+        .collect())
+}
+
+/// The date Gmail received the message, from `internalDate`.
+pub fn message_date(message: &Message) -> Option<DateTime<Utc>> {
+    message
+        .internal_date
+        .and_then(DateTime::<Utc>::from_timestamp_millis)
+}
+
+/// Addresses seen during one processing cycle.
+#[derive(Debug, Default)]
+pub struct Discovery {
+    pub sightings: BTreeMap<String, Sighting>,
+}
+
+impl Discovery {
+    /// Records a message for each recipient. Messages not newer than the recorded
+    /// `last_seen` were counted by an earlier cycle and are skipped.
+    pub fn observe(
+        &mut self,
+        recipients: &[String],
+        at: DateTime<Utc>,
+        sender_domain: Option<&str>,
+        state: &State,
+    ) {
+        for recipient in recipients {
+            let counted = state
+                .stats(recipient)
+                .and_then(|stats| stats.last_seen)
+                .is_some_and(|last_seen| at <= last_seen);
+            if counted {
+                continue;
             }
+
+            self.sightings
+                .entry(recipient.clone())
+                .and_modify(|sighting| sighting.add(at, sender_domain))
+                .or_insert_with(|| Sighting::new(at, sender_domain));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sightings.is_empty()
+    }
+}
+
+/// The parts of a message that routing decisions look at.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFacts {
+    /// Local parts of recipients on the routed domain
+    pub recipients: Vec<String>,
+    pub from: Option<String>,
+    pub reply_to: Option<String>,
+    pub return_path: Option<String>,
+    pub subject: Option<String>,
+    /// Text of the plain and HTML body parts
+    pub body: String,
+    /// Gmail's size estimate in bytes
+    pub size: Option<u64>,
+    /// MIME types of the attachments
+    pub attachments: Vec<String>,
+    pub labels: Vec<String>,
+    pub received: Option<DateTime<Utc>>,
+    pub thread_id: Option<String>,
+}
+
+impl MessageFacts {
+    pub fn from_message(message: &Message, domain: &str) -> Result<Self> {
+        let mut facts = Self {
+            recipients: extract_recipients(message, domain)?,
+            from: extract_address(message, "from"),
+            reply_to: extract_address(message, "reply-to"),
+            return_path: extract_address(message, "return-path"),
+            subject: header_value(message, "subject"),
+            size: message
+                .size_estimate
+                .and_then(|size| u64::try_from(size).ok()),
+            labels: message.label_ids.clone().unwrap_or_default(),
+            received: message_date(message),
+            thread_id: message.thread_id.clone(),
+            ..Default::default()
+        };
+        if let Some(payload) = &message.payload {
+            facts.collect_parts(payload);
+        }
+        Ok(facts)
+    }
+
+    fn collect_parts(&mut self, part: &MessagePart) {
+        let mime_type = part.mime_type.as_deref().unwrap_or_default();
+        let is_attachment = part.filename.as_deref().is_some_and(|f| !f.is_empty())
+            || part
+                .body
+                .as_ref()
+                .is_some_and(|body| body.attachment_id.is_some());
+
+        if is_attachment {
+            self.attachments.push(mime_type.to_lowercase());
+        } else if mime_type.starts_with("text/") {
+            if let Some(data) = part.body.as_ref().and_then(|body| body.data.as_ref()) {
+                if !self.body.is_empty() {
+                    self.body.push('\n');
+                }
+                self.body.push_str(&String::from_utf8_lossy(data));
+            }
+        }
+
+        for child in part.parts.iter().flatten() {
+            self.collect_parts(child);
         }
     }
 
-    Ok(recipients)
+    pub fn sender_domain(&self) -> Option<&str> {
+        self.from.as_deref().and_then(address_domain)
+    }
+}
+
+/// Domain of the `From` address, lowercased.
+pub fn extract_sender_domain(message: &Message) -> Option<String> {
+    let from = header_value(message, "from")?;
+    parse_address_domain(&from)
+}
+
+/// The address in a single-address header such as `From` or `Return-Path`, lowercased.
+pub fn extract_address(message: &Message, header: &str) -> Option<String> {
+    parse_address(&header_value(message, header)?)
+}
+
+pub fn address_domain(address: &str) -> Option<&str> {
+    address.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+pub fn header_value(message: &Message, name: &str) -> Option<String> {
+    message
+        .payload
+        .as_ref()?
+        .headers
+        .as_ref()?
+        .iter()
+        .find(|h| {
+            h.name
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+        .and_then(|h| h.value.clone())
+}
+
+/// The address in `"Name <user@domain>"` or `user@domain`, lowercased.
+fn parse_address(value: &str) -> Option<String> {
+    let email = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    let email = email.trim().trim_end_matches('.').to_lowercase();
+    let (local, domain) = email.rsplit_once('@')?;
+    (!local.is_empty() && !domain.is_empty()).then_some(email)
+}
+
+/// Domain part of `"Name <user@domain>"` or `user@domain`.
+fn parse_address_domain(value: &str) -> Option<String> {
+    let address = parse_address(value)?;
+    address_domain(&address).map(str::to_string)
 }
 
 /// Supported formats: "email@domain.com", "Name <email@domain.com>", "email1, email2"
@@ -51,12 +247,15 @@ fn parse_email_addresses(header_value: &str, domain: &str) -> Vec<String> {
     addresses
 }
 
+/// Fetches every message and records its recipients, skipping messages already
+/// counted in `state`.
 pub async fn collect_all_addresses(
     gmail_client: &crate::gmail::GmailClient,
     message_ids: &[String],
     domain: &str,
-) -> Result<HashSet<String>> {
-    let mut all_addresses = HashSet::new();
+    state: &State,
+) -> Result<Discovery> {
+    let mut discovery = Discovery::default();
 
     for (idx, msg_id) in message_ids.iter().enumerate() {
         if idx % 100 == 0 {
@@ -65,25 +264,164 @@ pub async fn collect_all_addresses(
 
         let message = gmail_client.get_message(msg_id).await?;
         let recipients = extract_recipients(&message, domain)?;
+        let received = message_date(&message).unwrap_or_else(Utc::now);
+        let sender_domain = extract_sender_domain(&message);
 
-        for recipient in recipients {
-            all_addresses.insert(recipient);
-        }
+        discovery.observe(&recipients, received, sender_domain.as_deref(), state);
+    }
+
+    Ok(discovery)
+}
+
+/// Outcome of routing a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    /// `None` keeps the message
+    pub action: Option<Action>,
+    /// Index of the rule that decided, if any
+    pub rule: Option<usize>,
+    /// Kept because a recipient is protected; rules were not applied
+    pub protected: bool,
+}
+
+/// Keeps mail to a protected address; otherwise applies the first matching rule,
+/// or the address table if no rule matches.
+pub fn route(facts: &MessageFacts, routing_config: &RoutingConfig) -> Decision {
+    route_at(facts, routing_config, Utc::now())
+}
+
+/// Like [`route`], with rule ages measured from `now`.
+pub fn route_at(
+    facts: &MessageFacts,
+    routing_config: &RoutingConfig,
+    now: DateTime<Utc>,
+) -> Decision {
+    if facts
+        .recipients
+        .iter()
+        .any(|r| routing_config.is_protected(r))
+    {
+        return Decision {
+            action: None,
+            rule: None,
+            protected: true,
+        };
+    }
+
+    let matched = routing_config
+        .rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.when.matches_at(facts, now));
+
+    match matched {
+        Some((index, rule)) => Decision {
+            action: rule.action.action(),
+            rule: Some(index),
+            protected: false,
+        },
+        None => Decision {
+            action: decide_action(&facts.recipients, routing_config),
+            rule: None,
+            protected: false,
+        },
+    }
+}
+
+/// How one rule fared against a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleOutcome {
+    Matched,
+    /// The condition fields that did not match
+    Failed(Vec<&'static str>),
+    /// An earlier rule already matched
+    NotReached,
+}
+
+/// Evaluates every rule in order the way [`route`] does, reporting each result.
+pub fn evaluate_rules(
+    facts: &MessageFacts,
+    routing_config: &RoutingConfig,
+    now: DateTime<Utc>,
+) -> Vec<RuleOutcome> {
+    let mut matched = false;
+    routing_config
+        .rules
+        .iter()
+        .map(|rule| {
+            if matched {
+                return RuleOutcome::NotReached;
+            }
+            let mismatches = rule.when.mismatches_at(facts, now);
+            matched = mismatches.is_empty();
+            if matched {
+                RuleOutcome::Matched
+            } else {
+                RuleOutcome::Failed(mismatches)
+            }
+        })
+        .collect()
+}
+
+/// Picks the action for a whole thread from the decisions for its messages. A
+/// message to a protected recipient or kept by an explicit rule keeps the
+/// thread; otherwise the first routed message decides.
+pub fn thread_action(decisions: &[Decision]) -> Option<Action> {
+    if decisions
+        .iter()
+        .any(|d| d.protected || (d.rule.is_some() && d.action.is_none()))
+    {
+        return None;
     }
+    decisions.iter().find_map(|d| d.action)
+}
 
-    Ok(all_addresses)
+/// Whether the user sent the message.
+pub fn is_sent(message: &Message) -> bool {
+    message
+        .label_ids
+        .as_ref()
+        .is_some_and(|labels| labels.iter().any(|l| l == "SENT"))
 }
 
-pub fn should_delete_message(
+/// Routes a message by its recipients according to the configured conflict policy.
+/// Returns `None` if the message is kept.
+pub fn decide_action(
     recipients: &[String],
     routing_config: &crate::config::RoutingConfig,
-) -> bool {
-    for recipient in recipients {
-        if !routing_config.is_allowed(recipient) {
-            return true;
-        }
+) -> Option<Action> {
+    if recipients.iter().any(|r| routing_config.is_protected(r)) {
+        return None;
     }
-    false
+
+    let deciding: Vec<&String> = match routing_config.conflict_policy {
+        ConflictPolicy::AnyBlocked => recipients.iter().collect(),
+        ConflictPolicy::AllBlocked => {
+            if recipients.iter().any(|r| routing_config.is_allowed(r)) {
+                return None;
+            }
+            recipients.iter().collect()
+        }
+        ConflictPolicy::Priority => {
+            let top = recipients
+                .iter()
+                .map(|r| routing_config.priority(r))
+                .max()?;
+            let deciding: Vec<_> = recipients
+                .iter()
+                .filter(|r| routing_config.priority(r) == top)
+                .collect();
+            if deciding.iter().any(|r| routing_config.is_allowed(r)) {
+                return None;
+            }
+            deciding
+        }
+    };
+
+    deciding
+        .into_iter()
+        .find(|r| !routing_config.is_allowed(r))
+        .map(|r| routing_config.action_for(r))
 }
 
 #[cfg(test)]
@@ -111,23 +449,330 @@ mod tests {
     }
 
     #[test]
-    fn test_should_delete_message() {
+    fn test_recipient_sources() {
+        use google_gmail1::api::MessagePartHeader;
+
+        let header = |name: &str, value: &str| MessagePartHeader {
+            name: Some(name.to_string()),
+            value: Some(value.to_string()),
+        };
+        let message = Message {
+            payload: Some(MessagePart {
+                headers: Some(vec![
+                    header("To", "Shop <shop@example.com>, friend@other.com"),
+                    header("Cc", "news@example.com"),
+                    header("Delivered-To", "shop@example.com"),
+                    header("Subject", "me@example.com"),
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let sources = recipient_sources(&message, "example.com").unwrap();
+        let found: Vec<_> = sources
+            .iter()
+            .map(|s| (s.local_part.as_str(), s.header.as_str(), s.is_routed()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("shop", "To", true),
+                ("news", "Cc", false),
+                ("shop", "Delivered-To", false),
+            ]
+        );
+        assert_eq!(
+            extract_recipients(&message, "example.com").unwrap(),
+            vec!["shop"]
+        );
+    }
+
+    #[test]
+    fn test_parse_address_domain() {
+        assert_eq!(
+            parse_address_domain("Shop <Orders@Mail.Shop.com>"),
+            Some("mail.shop.com".to_string())
+        );
+        assert_eq!(
+            parse_address_domain("news@example.org"),
+            Some("example.org".to_string())
+        );
+        assert_eq!(parse_address_domain("undisclosed"), None);
+    }
+
+    #[test]
+    fn test_discovery_skips_counted_messages() {
+        let at = |day| format!("2024-01-{:02}T00:00:00Z", day).parse().unwrap();
+        let mut state = State::default();
+        state.record_sighting("shop", &Sighting::new(at(5), None));
+
+        let mut discovery = Discovery::default();
+        let recipients = ["shop".to_string(), "new".to_string()];
+        discovery.observe(&recipients, at(5), None, &state);
+        discovery.observe(&recipients, at(6), Some("shop.com"), &state);
+
+        assert_eq!(discovery.sightings["shop"].messages, 1);
+        assert_eq!(discovery.sightings["shop"].first_seen, at(6));
+        assert_eq!(discovery.sightings["new"].messages, 2);
+        assert_eq!(discovery.sightings["new"].first_seen, at(5));
+        assert_eq!(discovery.sightings["new"].sender_domains["shop.com"], 1);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            parse_address("<Bounce@Mail.Shop.com>"),
+            Some("bounce@mail.shop.com".to_string())
+        );
+        assert_eq!(parse_address("<>"), None);
+    }
+
+    #[test]
+    fn test_route_rules_before_addresses() {
+        use crate::rules::{Condition, Rule, RuleAction};
+
+        let mut config = RoutingConfig::default();
+        config.set_allowed("shop", false);
+        config.rules = vec![
+            Rule {
+                name: None,
+                when: Condition {
+                    to: Some("shop".to_string()),
+                    from_domain: Some("shop.com".to_string()),
+                    ..Default::default()
+                },
+                action: RuleAction::Allow,
+            },
+            Rule {
+                name: None,
+                when: Condition {
+                    to: Some("shop".to_string()),
+                    ..Default::default()
+                },
+                action: RuleAction::Spam,
+            },
+        ];
+
+        let facts = |from: &str| MessageFacts {
+            recipients: vec!["shop".to_string()],
+            from: Some(from.to_string()),
+            ..Default::default()
+        };
+
+        let decision = route(&facts("orders@shop.com"), &config);
+        assert_eq!(decision.action, None);
+        assert_eq!(decision.rule, Some(0));
+
+        let decision = route(&facts("deals@spam.example"), &config);
+        assert_eq!(decision.action, Some(Action::Spam));
+        assert_eq!(decision.rule, Some(1));
+
+        let now = Utc::now();
+        assert_eq!(
+            evaluate_rules(&facts("orders@shop.com"), &config, now),
+            vec![RuleOutcome::Matched, RuleOutcome::NotReached]
+        );
+        assert_eq!(
+            evaluate_rules(&facts("deals@spam.example"), &config, now),
+            vec![
+                RuleOutcome::Failed(vec!["from_domain"]),
+                RuleOutcome::Matched
+            ]
+        );
+
+        config.rules.clear();
+        let decision = route(&facts("orders@shop.com"), &config);
+        assert_eq!(decision.action, Some(Action::Delete));
+        assert_eq!(decision.rule, None);
+    }
+
+    #[test]
+    fn test_rules_skip_protected_recipients() {
+        let config: RoutingConfig = serde_yaml::from_str(
+            "addresses:\n  me:\n    allowed: true\n    protected: true\n  shop: false\n\
+             rules:\n  - when:\n      from_domain: spam.example\n    action: delete\n",
+        )
+        .unwrap();
+        let facts = |recipients: &[&str]| MessageFacts {
+            recipients: recipients.iter().map(|r| r.to_string()).collect(),
+            from: Some("deals@spam.example".to_string()),
+            ..Default::default()
+        };
+
+        let decision = route(&facts(&["shop", "me"]), &config);
+        assert_eq!(decision.action, None);
+        assert_eq!(decision.rule, None);
+        assert!(decision.protected);
+
+        let decision = route(&facts(&["shop"]), &config);
+        assert_eq!(decision.action, Some(Action::Delete));
+        assert_eq!(decision.rule, Some(0));
+        assert!(!decision.protected);
+    }
+
+    #[test]
+    fn test_decide_action() {
         use crate::config::RoutingConfig;
 
         let mut config = RoutingConfig::default();
-        config.addresses.insert("allowed".to_string(), true);
-        config.addresses.insert("blocked".to_string(), false);
+        config.addresses.insert("allowed".to_string(), true.into());
+        config.addresses.insert("blocked".to_string(), false.into());
+        config.addresses.insert("spammy".to_string(), false.into());
+        config.set_action("spammy", Action::Spam);
+
+        assert_eq!(decide_action(&["allowed".to_string()], &config), None);
+
+        assert_eq!(
+            decide_action(&["blocked".to_string()], &config),
+            Some(Action::Delete)
+        );
+
+        assert_eq!(
+            decide_action(&["allowed".to_string(), "blocked".to_string()], &config),
+            Some(Action::Delete)
+        );
+
+        assert_eq!(
+            decide_action(&["spammy".to_string()], &config),
+            Some(Action::Spam)
+        );
+    }
+
+    #[test]
+    fn test_thread_action() {
+        let decision = |action, rule| Decision {
+            action,
+            rule,
+            protected: false,
+        };
+
+        assert_eq!(
+            thread_action(&[
+                decision(None, None),
+                decision(Some(Action::Spam), None),
+                decision(Some(Action::Delete), Some(1)),
+            ]),
+            Some(Action::Spam)
+        );
+        assert_eq!(
+            thread_action(&[decision(Some(Action::Spam), None), decision(None, Some(0))]),
+            None
+        );
+        assert_eq!(thread_action(&[decision(None, None)]), None);
+
+        let protected = Decision {
+            protected: true,
+            ..decision(None, None)
+        };
+        assert_eq!(
+            thread_action(&[decision(Some(Action::Delete), None), protected]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_sent() {
+        let message = Message {
+            label_ids: Some(vec!["SENT".to_string(), "INBOX".to_string()]),
+            ..Default::default()
+        };
+        assert!(is_sent(&message));
+        assert!(!is_sent(&Message::default()));
+    }
+
+    fn multi_recipient_config(policy: ConflictPolicy) -> RoutingConfig {
+        let yaml = r#"
+addresses:
+  me:
+    allowed: true
+    priority: 10
+  shop: false
+  spammy: false
+  old:
+    allowed: false
+    priority: 10
+  admin:
+    allowed: false
+    protected: true
+actions:
+  spammy: spam
+"#;
+        let mut config: RoutingConfig = serde_yaml::from_str(yaml).unwrap();
+        config.conflict_policy = policy;
+        config
+    }
 
-        assert!(!should_delete_message(
-            &vec!["allowed".to_string()],
-            &config
-        ));
+    fn recipients(list: &[&str]) -> Vec<String> {
+        list.iter().map(|r| r.to_string()).collect()
+    }
 
-        assert!(should_delete_message(&vec!["blocked".to_string()], &config));
+    #[test]
+    fn test_any_blocked_policy() {
+        let config = multi_recipient_config(ConflictPolicy::AnyBlocked);
 
-        assert!(should_delete_message(
-            &vec!["allowed".to_string(), "blocked".to_string()],
-            &config
-        ));
+        assert_eq!(
+            decide_action(&recipients(&["me", "shop"]), &config),
+            Some(Action::Delete)
+        );
+        assert_eq!(
+            decide_action(&recipients(&["unknown", "spammy", "shop"]), &config),
+            Some(Action::Spam)
+        );
+        assert_eq!(
+            decide_action(&recipients(&["me", "unknown"]), &config),
+            None
+        );
+    }
+
+    #[test]
+    fn test_all_blocked_policy() {
+        let config = multi_recipient_config(ConflictPolicy::AllBlocked);
+
+        assert_eq!(decide_action(&recipients(&["me", "shop"]), &config), None);
+        assert_eq!(
+            decide_action(&recipients(&["shop", "unknown"]), &config),
+            None
+        );
+        assert_eq!(
+            decide_action(&recipients(&["spammy", "shop"]), &config),
+            Some(Action::Spam)
+        );
+    }
+
+    #[test]
+    fn test_priority_policy() {
+        let config = multi_recipient_config(ConflictPolicy::Priority);
+
+        // `me` outranks `shop`
+        assert_eq!(decide_action(&recipients(&["shop", "me"]), &config), None);
+        // `old` outranks `unknown`, which would be allowed
+        assert_eq!(
+            decide_action(&recipients(&["unknown", "old"]), &config),
+            Some(Action::Delete)
+        );
+        // Equal priority: allowed wins the tie
+        assert_eq!(decide_action(&recipients(&["old", "me"]), &config), None);
+        assert_eq!(
+            decide_action(&recipients(&["shop", "spammy"]), &config),
+            Some(Action::Delete)
+        );
+    }
+
+    #[test]
+    fn test_protected_wins_under_every_policy() {
+        for policy in [
+            ConflictPolicy::AnyBlocked,
+            ConflictPolicy::AllBlocked,
+            ConflictPolicy::Priority,
+        ] {
+            let config = multi_recipient_config(policy);
+            assert_eq!(
+                decide_action(&recipients(&["shop", "spammy", "admin"]), &config),
+                None,
+                "{:?}",
+                policy
+            );
+        }
     }
 }
@@ -0,0 +1,195 @@
+use crate::config::Action;
+use crate::gmail::GmailClient;
+use crate::journal::Recorder;
+use crate::processor::MessageFacts;
+use crate::state::State;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Gmail label holding quarantined messages.
+pub const QUARANTINE_LABEL: &str = "gmail_router/quarantine";
+
+/// `quarantine` section of credentials.yaml.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuarantineConfig {
+    /// Days after quarantining before a message expires
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+    #[serde(default)]
+    pub expire_action: ExpireAction,
+}
+
+fn default_retention_days() -> u32 {
+    30
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            retention_days: default_retention_days(),
+            expire_action: ExpireAction::default(),
+        }
+    }
+}
+
+/// What happens to a quarantined message once it expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpireAction {
+    #[default]
+    Delete,
+    Trash,
+}
+
+impl From<ExpireAction> for Action {
+    fn from(action: ExpireAction) -> Self {
+        match action {
+            ExpireAction::Delete => Action::Delete,
+            ExpireAction::Trash => Action::Trash,
+        }
+    }
+}
+
+/// Brings `tracked`, the quarantine time of each message, in line with the
+/// messages now in quarantine and returns those past the retention period. A
+/// message not tracked yet was quarantined since the last check and counts from
+/// `now`.
+pub fn track(
+    tracked: &mut BTreeMap<String, DateTime<Utc>>,
+    quarantined: &[String],
+    retention_days: u32,
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    tracked.retain(|id, _| quarantined.contains(id));
+    for id in quarantined {
+        tracked.entry(id.clone()).or_insert(now);
+    }
+
+    let retention = TimeDelta::days(retention_days.into());
+    tracked
+        .iter()
+        .filter(|(_, since)| now - **since >= retention)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// IDs of all quarantined messages.
+pub async fn list(client: &GmailClient) -> Result<Vec<String>> {
+    match client.find_label(QUARANTINE_LABEL).await? {
+        Some(label_id) => client.search("", &[label_id]).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Returns a quarantined message to the inbox, journaled as undoing its quarantine.
+pub async fn release(
+    client: &GmailClient,
+    domain: &str,
+    journal: &Recorder,
+    message_id: &str,
+) -> Result<()> {
+    let label_id = client.find_label(QUARANTINE_LABEL).await?;
+    let message = client.get_message(message_id).await?;
+    let facts = MessageFacts::from_message(&message, domain)?;
+    if !label_id.is_some_and(|id| facts.labels.contains(&id)) {
+        bail!("Message {} is not quarantined", message_id);
+    }
+
+    journal.release(client, message_id, &facts).await
+}
+
+/// Applies the expire action to messages quarantined longer than the retention
+/// period. Quarantine times are kept in the state file at `state_path`.
+/// Returns the number of messages removed.
+pub async fn purge_expired(
+    client: &GmailClient,
+    config: &QuarantineConfig,
+    domain: &str,
+    journal: &Recorder,
+    state_path: &Path,
+) -> Result<usize> {
+    let Some(label_id) = client.find_label(QUARANTINE_LABEL).await? else {
+        return Ok(0);
+    };
+
+    let quarantined = client.search("", &[label_id]).await?;
+    let mut tracked = State::load(state_path)?.quarantined;
+    let expired = track(
+        &mut tracked,
+        &quarantined,
+        config.retention_days,
+        Utc::now(),
+    );
+    let action = Action::from(config.expire_action);
+    let mut purged = 0;
+    for message_id in &expired {
+        let result = match client.get_message(message_id).await {
+            Ok(message) => match MessageFacts::from_message(&message, domain) {
+                Ok(facts) => {
+                    journal
+                        .apply(client, message_id, action, &facts, None)
+                        .await
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                tracked.remove(message_id);
+                purged += 1;
+            }
+            Err(e) => warn!(
+                "Failed to expire quarantined message {}: {:#}",
+                message_id, e
+            ),
+        }
+    }
+
+    State::update(state_path, |state| state.quarantined = tracked)
+        .context("Failed to save quarantine times")?;
+
+    if purged > 0 {
+        info!("Expired {} quarantined messages ({})", purged, action);
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: QuarantineConfig = serde_yaml::from_str("expire_action: trash").unwrap();
+        assert_eq!(config.retention_days, 30);
+        assert_eq!(Action::from(config.expire_action), Action::Trash);
+    }
+
+    #[test]
+    fn test_track_counts_from_quarantine() {
+        let now: DateTime<Utc> = "2024-06-12T08:00:00Z".parse().unwrap();
+        let mut tracked = BTreeMap::new();
+        tracked.insert("released".to_string(), now - TimeDelta::days(40));
+        tracked.insert("waiting".to_string(), now - TimeDelta::days(10));
+        tracked.insert("expired".to_string(), now - TimeDelta::days(30));
+
+        // "old" was received long ago but only quarantined in this cycle
+        let quarantined = ["old", "waiting", "expired"].map(String::from);
+        assert_eq!(track(&mut tracked, &quarantined, 30, now), vec!["expired"]);
+        assert_eq!(tracked.get("old"), Some(&now));
+        assert!(!tracked.contains_key("released"));
+
+        let later = now + TimeDelta::days(30);
+        assert_eq!(
+            track(&mut tracked, &quarantined, 30, later),
+            vec!["expired", "old", "waiting"]
+        );
+        assert_eq!(track(&mut tracked, &quarantined, 0, now).len(), 3);
+    }
+}
@@ -0,0 +1,204 @@
+//! Runs the routing decision over local message files, so rule changes can be
+//! tried without a mailbox and checked in CI against expected actions.
+
+use crate::config::{write_atomic, Action, RoutingConfig};
+use crate::{backup, eml, processor};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The result name of a message that is left alone.
+pub const KEEP: &str = "keep";
+
+/// How routing decided one message.
+#[derive(Debug, Clone, Serialize)]
+pub struct Case {
+    /// File path, followed by `#n` for the n-th message of an mbox file
+    pub name: String,
+    pub recipients: Vec<String>,
+    pub from: Option<String>,
+    pub subject: Option<String>,
+    /// The rule that decided, if any
+    pub rule: Option<String>,
+    /// `keep` or the action applied
+    pub action: String,
+    /// The action in the expectations file, if it lists the message
+    pub expected: Option<String>,
+}
+
+impl Case {
+    pub fn passed(&self) -> bool {
+        self.expected
+            .as_ref()
+            .is_none_or(|expected| *expected == self.action)
+    }
+}
+
+/// Routes every message in `paths`: .eml or Maildir message files, .mbox files,
+/// or folders of them.
+pub fn run(
+    paths: &[PathBuf],
+    domain: &str,
+    routing_config: &RoutingConfig,
+    now: DateTime<Utc>,
+) -> Result<Vec<Case>> {
+    let mut cases = Vec::new();
+
+    for path in paths {
+        for (name, raw) in backup::read_named_messages(path)? {
+            let message = eml::parse(&raw).with_context(|| format!("Failed to parse {}", name))?;
+            let facts = processor::MessageFacts::from_message(&message, domain)
+                .with_context(|| format!("Failed to read {}", name))?;
+            let decision = processor::route_at(&facts, routing_config, now);
+
+            cases.push(Case {
+                name,
+                rule: decision
+                    .rule
+                    .map(|index| routing_config.rules[index].label(index)),
+                action: decision
+                    .action
+                    .map_or(KEEP.to_string(), |action| action.to_string()),
+                recipients: facts.recipients,
+                from: facts.from,
+                subject: facts.subject,
+                expected: None,
+            });
+        }
+    }
+
+    Ok(cases)
+}
+
+/// Reads an expectations file: a YAML map from message name to `keep` or an action.
+pub fn load_expectations(path: &Path) -> Result<BTreeMap<String, String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read expectations {:?}", path))?;
+    let expectations: BTreeMap<String, String> = serde_yaml::from_str(&contents)
+        .with_context(|| format!("Failed to parse expectations {:?}", path))?;
+
+    for (name, action) in &expectations {
+        if action != KEEP {
+            action
+                .parse::<Action>()
+                .with_context(|| format!("Invalid expectation for {}", name))?;
+        }
+    }
+
+    Ok(expectations)
+}
+
+/// Fills in the expected action of every case. Returns the names of expected
+/// messages that none of the cases has.
+pub fn apply_expectations(
+    cases: &mut [Case],
+    mut expectations: BTreeMap<String, String>,
+) -> Vec<String> {
+    for case in cases.iter_mut() {
+        case.expected = expectations.remove(&case.name);
+    }
+    expectations.into_keys().collect()
+}
+
+/// Writes the actions of `cases` as the new expectations.
+pub fn save_expectations(path: &Path, cases: &[Case]) -> Result<()> {
+    let expectations: BTreeMap<_, _> = cases
+        .iter()
+        .map(|case| (case.name.as_str(), case.action.as_str()))
+        .collect();
+    let contents = format!(
+        "# Expected actions for `gmail_router test-rules`; regenerate with --update\n{}",
+        serde_yaml::to_string(&expectations)?
+    );
+    write_atomic(path, &contents).context("Failed to write expectations")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "gmail_router_test_rule_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_run_and_expectations() {
+        let dir = temp_dir("run");
+        fs::write(
+            dir.join("receipt.eml"),
+            "From: orders@shop.com\nTo: shop@example.com\nSubject: Receipt\n\nThanks\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("old.mbox"),
+            "From MAILER-DAEMON Wed Jun 12 08:30:00 2024\nFrom: a@spam.example\nTo: shop@example.com\n\nBuy\n\n\
+             From MAILER-DAEMON Wed Jun 12 08:30:00 2024\nFrom: b@friend.example\nTo: me@example.com\n\nHi\n",
+        )
+        .unwrap();
+        let routing: RoutingConfig = serde_yaml::from_str(
+            "addresses:\n  shop: false\nactions:\n  shop: spam\nrules:\n  - name: shop receipts\n    when:\n      from_domain: shop.com\n    action: allow\n",
+        )
+        .unwrap();
+
+        let mut cases = run(
+            std::slice::from_ref(&dir),
+            "example.com",
+            &routing,
+            Utc::now(),
+        )
+        .unwrap();
+        let expectations_path = dir.join("expected.yaml");
+        save_expectations(&expectations_path, &cases).unwrap();
+        let mut expectations = load_expectations(&expectations_path).unwrap();
+        expectations.insert(format!("{}/gone.eml", dir.display()), KEEP.to_string());
+        expectations.insert(format!("{}/old.mbox#1", dir.display()), "trash".to_string());
+        let missing = apply_expectations(&mut cases, expectations);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let results: Vec<_> = cases
+            .iter()
+            .map(|case| {
+                let name = case.name.rsplit('/').next().unwrap();
+                (
+                    name,
+                    case.action.as_str(),
+                    case.rule.as_deref(),
+                    case.passed(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("old.mbox#1", "spam", None, false),
+                ("old.mbox#2", "keep", None, true),
+                ("receipt.eml", "keep", Some("shop receipts"), true),
+            ]
+        );
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].ends_with("gone.eml"));
+    }
+
+    #[test]
+    fn test_invalid_expectation() {
+        let dir = temp_dir("invalid");
+        let path = dir.join("expected.yaml");
+        fs::write(&path, "a.eml: archive\n").unwrap();
+
+        let result = load_expectations(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("Invalid expectation for a.eml"), "{}", error);
+    }
+}
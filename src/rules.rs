@@ -0,0 +1,612 @@
+use crate::config::Action;
+use crate::processor::{address_domain, MessageFacts};
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+
+/// An entry of the `rules` list in routing.yaml. Rules are checked in order
+/// before the address table, and the first one whose condition matches decides.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub when: Condition,
+    pub action: RuleAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Keep the message, even if a recipient is blocked
+    Allow,
+    Delete,
+    Trash,
+    Spam,
+    Quarantine,
+}
+
+impl RuleAction {
+    /// The action to apply, or `None` to keep the message.
+    pub fn action(self) -> Option<Action> {
+        match self {
+            RuleAction::Allow => None,
+            RuleAction::Delete => Some(Action::Delete),
+            RuleAction::Trash => Some(Action::Trash),
+            RuleAction::Spam => Some(Action::Spam),
+            RuleAction::Quarantine => Some(Action::Quarantine),
+        }
+    }
+}
+
+impl std::fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.action() {
+            Some(action) => action.fmt(f),
+            None => f.write_str("allow"),
+        }
+    }
+}
+
+/// Conditions on a message; every field that is set must match.
+///
+/// Address fields take patterns where `*` matches any characters. Domain fields
+/// also match subdomains, so `shop.com` matches `mail.shop.com`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    /// Every nested condition must match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub all: Vec<Condition>,
+    /// At least one nested condition must match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub any: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not: Option<Box<Condition>>,
+
+    /// Local part of a recipient on the routed domain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to_domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_path_domain: Option<String>,
+
+    /// Case-insensitive regular expression searched in the subject
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Pattern>,
+    /// Text searched case-insensitively in the plain and HTML body parts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub larger_than: Option<Size>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smaller_than: Option<Size>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_attachment: Option<bool>,
+    /// MIME type of any attachment, e.g. `application/pdf` or `image/*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment_type: Option<String>,
+    /// Gmail label ID already on the message, e.g. `CATEGORY_PROMOTIONS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<Age>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub newer_than: Option<Age>,
+}
+
+impl Rule {
+    /// Name for logs: the configured name or the position in the list.
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("rule #{}", index + 1),
+        }
+    }
+}
+
+impl Condition {
+    pub fn is_empty(&self) -> bool {
+        *self == Condition::default()
+    }
+
+    pub fn matches(&self, facts: &MessageFacts) -> bool {
+        self.matches_at(facts, Utc::now())
+    }
+
+    /// Like [`Condition::matches`], with ages measured from `now`.
+    pub fn matches_at(&self, facts: &MessageFacts, now: DateTime<Utc>) -> bool {
+        self.mismatches_at(facts, now).is_empty()
+    }
+
+    /// Names of the fields of this condition that do not match the message; empty
+    /// if the condition matches. Nested conditions are reported as `all`, `any` or `not`.
+    pub fn mismatches_at(&self, facts: &MessageFacts, now: DateTime<Utc>) -> Vec<&'static str> {
+        let address = |pattern: &Option<String>, value: &Option<String>| match pattern {
+            None => true,
+            Some(pattern) => value.as_deref().is_some_and(|v| glob_match(pattern, v)),
+        };
+        let domain = |pattern: &Option<String>, value: &Option<String>| match pattern {
+            None => true,
+            Some(pattern) => value
+                .as_deref()
+                .and_then(address_domain)
+                .is_some_and(|d| domain_matches(pattern, d)),
+        };
+        let age = facts.received.map(|received| now - received);
+        let has_attachment = !facts.attachments.is_empty();
+
+        let checks = [
+            (
+                "to",
+                self.to
+                    .as_ref()
+                    .is_none_or(|pattern| facts.recipients.iter().any(|r| glob_match(pattern, r))),
+            ),
+            ("from", address(&self.from, &facts.from)),
+            ("from_domain", domain(&self.from_domain, &facts.from)),
+            ("reply_to", address(&self.reply_to, &facts.reply_to)),
+            (
+                "reply_to_domain",
+                domain(&self.reply_to_domain, &facts.reply_to),
+            ),
+            (
+                "return_path",
+                address(&self.return_path, &facts.return_path),
+            ),
+            (
+                "return_path_domain",
+                domain(&self.return_path_domain, &facts.return_path),
+            ),
+            (
+                "subject",
+                self.subject.as_ref().is_none_or(|pattern| {
+                    facts
+                        .subject
+                        .as_deref()
+                        .is_some_and(|subject| pattern.0.is_match(subject))
+                }),
+            ),
+            (
+                "body",
+                self.body
+                    .as_ref()
+                    .is_none_or(|text| facts.body.to_lowercase().contains(&text.to_lowercase())),
+            ),
+            (
+                "larger_than",
+                self.larger_than
+                    .is_none_or(|limit| facts.size.is_some_and(|size| size > limit.0)),
+            ),
+            (
+                "smaller_than",
+                self.smaller_than
+                    .is_none_or(|limit| facts.size.is_some_and(|size| size < limit.0)),
+            ),
+            (
+                "has_attachment",
+                self.has_attachment
+                    .is_none_or(|wanted| wanted == has_attachment),
+            ),
+            (
+                "attachment_type",
+                self.attachment_type.as_ref().is_none_or(|pattern| {
+                    facts
+                        .attachments
+                        .iter()
+                        .any(|mime| glob_match(pattern, mime))
+                }),
+            ),
+            (
+                "label",
+                self.label
+                    .as_ref()
+                    .is_none_or(|label| facts.labels.iter().any(|l| l.eq_ignore_ascii_case(label))),
+            ),
+            (
+                "older_than",
+                self.older_than
+                    .is_none_or(|limit| age.is_some_and(|age| age > limit.0)),
+            ),
+            (
+                "newer_than",
+                self.newer_than
+                    .is_none_or(|limit| age.is_some_and(|age| age < limit.0)),
+            ),
+            ("all", self.all.iter().all(|c| c.matches_at(facts, now))),
+            (
+                "any",
+                self.any.is_empty() || self.any.iter().any(|c| c.matches_at(facts, now)),
+            ),
+            (
+                "not",
+                self.not.as_ref().is_none_or(|c| !c.matches_at(facts, now)),
+            ),
+        ];
+
+        checks
+            .into_iter()
+            .filter(|(_, matched)| !matched)
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.is_empty() {
+            bail!("condition has no fields; it would match every message");
+        }
+        if self.to.as_deref().is_some_and(|to| to.contains('@')) {
+            bail!("`to` takes a local part without '@'");
+        }
+        for condition in self.all.iter().chain(&self.any).chain(self.not.as_deref()) {
+            condition.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// A regular expression, matched case-insensitively.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RegexBuilder::new(s)
+            .case_insensitive(true)
+            .build()
+            .map(Pattern)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A byte count written as `500`, `200K` or `5M`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (digits, multiplier) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 1024),
+            Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+            _ => (s, 1),
+        };
+        let value: u64 = digits
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid size {:?}, expected e.g. 500K or 5M", s))?;
+        value
+            .checked_mul(multiplier)
+            .map(Size)
+            .ok_or_else(|| format!("size {:?} is too large", s))
+    }
+}
+
+impl std::fmt::Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MB: u64 = 1024 * 1024;
+        match self.0 {
+            n if n >= MB && n % MB == 0 => write!(f, "{}M", n / MB),
+            n if n >= 1024 && n % 1024 == 0 => write!(f, "{}K", n / 1024),
+            n => write!(f, "{}", n),
+        }
+    }
+}
+
+/// A message age written as `45m`, `12h`, `30d` or `2w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Age(pub TimeDelta);
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("invalid age {:?}, expected e.g. 12h, 30d or 2w", s);
+        let (i, unit) = s.char_indices().last().ok_or_else(invalid)?;
+        let value: i64 = s[..i].trim().parse().map_err(|_| invalid())?;
+        let delta = match unit {
+            'm' => TimeDelta::try_minutes(value),
+            'h' => TimeDelta::try_hours(value),
+            'd' => TimeDelta::try_days(value),
+            'w' => TimeDelta::try_weeks(value),
+            _ => None,
+        };
+        delta.map(Age).ok_or_else(invalid)
+    }
+}
+
+impl std::fmt::Display for Age {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let minutes = self.0.num_minutes();
+        match minutes {
+            m if m % (7 * 24 * 60) == 0 && m != 0 => write!(f, "{}w", m / (7 * 24 * 60)),
+            m if m % (24 * 60) == 0 && m != 0 => write!(f, "{}d", m / (24 * 60)),
+            m if m % 60 == 0 && m != 0 => write!(f, "{}h", m / 60),
+            m => write!(f, "{}m", m),
+        }
+    }
+}
+
+macro_rules! serde_via_str {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                // Plain numbers in YAML arrive as integers
+                let value = serde_yaml::Value::deserialize(deserializer)?;
+                let text = match value {
+                    serde_yaml::Value::String(s) => s,
+                    serde_yaml::Value::Number(n) => n.to_string(),
+                    other => return Err(serde::de::Error::custom(format!("unexpected {:?}", other))),
+                };
+                text.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+serde_via_str!(Size, Age);
+
+/// Case-insensitive match where `*` stands for any run of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `domain` against `pattern` or any subdomain of it.
+pub fn domain_matches(pattern: &str, domain: &str) -> bool {
+    if pattern.contains('*') {
+        return glob_match(pattern, domain);
+    }
+    let pattern = pattern.to_lowercase();
+    let domain = domain.to_lowercase();
+    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(to: &str, from: &str) -> MessageFacts {
+        MessageFacts {
+            recipients: vec![to.to_string()],
+            from: Some(from.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("shop*", "shop123"));
+        assert!(glob_match("*@shop.com", "Orders@Shop.com"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("shop", "shop123"));
+        assert!(!glob_match("a*b", "acd"));
+    }
+
+    #[test]
+    fn test_domain_matches() {
+        assert!(domain_matches("shop.com", "shop.com"));
+        assert!(domain_matches("shop.com", "mail.shop.com"));
+        assert!(!domain_matches("shop.com", "evilshop.com"));
+    }
+
+    #[test]
+    fn test_condition_combines_recipient_and_sender() {
+        let condition = Condition {
+            to: Some("shop".to_string()),
+            from_domain: Some("shop.com".to_string()),
+            ..Default::default()
+        };
+
+        assert!(condition.matches(&facts("shop", "orders@mail.shop.com")));
+        assert!(!condition.matches(&facts("shop", "deals@spam.example")));
+        assert!(!condition.matches(&facts("other", "orders@shop.com")));
+        assert!(!condition.matches(&MessageFacts {
+            recipients: vec!["shop".to_string()],
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_mismatches_name_failed_fields() {
+        let condition = Condition {
+            to: Some("shop".to_string()),
+            from_domain: Some("shop.com".to_string()),
+            not: Some(Box::new(Condition {
+                subject: Some("invoice".parse().unwrap()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let now = Utc::now();
+
+        let mut message = facts("shop", "orders@shop.com");
+        assert!(condition.mismatches_at(&message, now).is_empty());
+
+        message.subject = Some("Your invoice".to_string());
+        assert_eq!(condition.mismatches_at(&message, now), vec!["not"]);
+
+        let message = facts("other", "deals@spam.example");
+        assert_eq!(
+            condition.mismatches_at(&message, now),
+            vec!["to", "from_domain"]
+        );
+    }
+
+    #[test]
+    fn test_composite_condition() {
+        let yaml = r#"
+all:
+  - subject: "(invoice|receipt) #\\d+"
+  - any:
+      - has_attachment: true
+      - body: payment due
+not:
+  from_domain: trusted.example
+larger_than: 10K
+newer_than: 2w
+"#;
+        let condition: Condition = serde_yaml::from_str(yaml).unwrap();
+        condition.validate().unwrap();
+
+        let now: DateTime<Utc> = "2024-06-30T00:00:00Z".parse().unwrap();
+        let message = MessageFacts {
+            from: Some("billing@shop.com".to_string()),
+            subject: Some("Your INVOICE #123".to_string()),
+            body: "Payment due by Friday".to_string(),
+            size: Some(20 * 1024),
+            received: Some("2024-06-25T00:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(condition.matches_at(&message, now));
+
+        let trusted = MessageFacts {
+            from: Some("billing@trusted.example".to_string()),
+            ..message.clone()
+        };
+        assert!(!condition.matches_at(&trusted, now));
+
+        let no_keyword = MessageFacts {
+            body: "hello".to_string(),
+            ..message.clone()
+        };
+        assert!(!condition.matches_at(&no_keyword, now));
+
+        let with_pdf = MessageFacts {
+            attachments: vec!["application/pdf".to_string()],
+            ..no_keyword
+        };
+        assert!(condition.matches_at(&with_pdf, now));
+
+        let old = MessageFacts {
+            received: Some("2024-01-01T00:00:00Z".parse().unwrap()),
+            ..message.clone()
+        };
+        assert!(!condition.matches_at(&old, now));
+
+        let small = MessageFacts {
+            size: Some(512),
+            ..message
+        };
+        assert!(!condition.matches_at(&small, now));
+    }
+
+    #[test]
+    fn test_attachment_type_and_label() {
+        let condition = Condition {
+            attachment_type: Some("image/*".to_string()),
+            label: Some("category_promotions".to_string()),
+            ..Default::default()
+        };
+        let message = MessageFacts {
+            attachments: vec!["image/png".to_string()],
+            labels: vec!["INBOX".to_string(), "CATEGORY_PROMOTIONS".to_string()],
+            ..Default::default()
+        };
+        assert!(condition.matches(&message));
+        assert!(!condition.matches(&MessageFacts {
+            labels: vec!["INBOX".to_string()],
+            ..message
+        }));
+    }
+
+    #[test]
+    fn test_size_and_age_round_trip() {
+        assert_eq!("5M".parse::<Size>().unwrap(), Size(5 * 1024 * 1024));
+        assert_eq!(
+            "99999999999999999M".parse::<Size>().unwrap_err(),
+            "size \"99999999999999999M\" is too large"
+        );
+        assert_eq!(Size(200 * 1024).to_string(), "200K");
+        assert!("5G".parse::<Size>().is_err());
+
+        assert_eq!("2w".parse::<Age>().unwrap().to_string(), "2w");
+        assert_eq!("36h".parse::<Age>().unwrap().to_string(), "36h");
+        assert!("10y".parse::<Age>().is_err());
+
+        let condition: Condition = serde_yaml::from_str("larger_than: 2048").unwrap();
+        assert_eq!(condition.larger_than, Some(Size(2048)));
+        assert_eq!(
+            serde_yaml::to_string(&condition).unwrap(),
+            "larger_than: 2K\n"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Condition::default().validate().is_err());
+        assert!(Condition {
+            to: Some("shop@example.com".to_string()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(Condition {
+            not: Some(Box::default()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(serde_yaml::from_str::<Condition>("subject: \"(unclosed\"").is_err());
+    }
+}
@@ -0,0 +1,499 @@
+//! Runtime state the router maintains itself, kept in state.json so that
+//! routing.yaml only holds what the user wrote.
+
+use crate::checkpoint::Checkpoint;
+use crate::config::{write_atomic, ConfigLock, RoutingConfig};
+use crate::labels::AliasLabel;
+use crate::yaml_edit::YamlDocument;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+/// How many sender domains are kept per address.
+pub const MAX_SENDER_DOMAINS: usize = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct State {
+    /// Start of the last address scan; the next one looks at mail since then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scanned_until: Option<DateTime<Utc>>,
+    /// Newest Gmail history ID among the processed messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_id: Option<u64>,
+    #[serde(default)]
+    pub checkpoint: Checkpoint,
+    /// What has been seen of each address
+    #[serde(default)]
+    pub addresses: BTreeMap<String, AddressStats>,
+    #[serde(default)]
+    pub counters: Counters,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<RunSummary>,
+    /// IDs of the Gmail filters exported from routing.yaml
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub filters: BTreeSet<String>,
+    /// Gmail labels of aliases and alias groups
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub alias_labels: BTreeMap<String, AliasLabel>,
+    /// When each message now in quarantine was first found there
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub quarantined: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// Mail seen for one local part.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct AddressStats {
+    /// Date of the oldest message seen for this address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<DateTime<Utc>>,
+    /// Date of the newest message seen for this address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Number of messages seen
+    #[serde(default)]
+    pub messages: u64,
+    /// Message count per sender domain, limited to the most frequent ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sender_domains: BTreeMap<String, u64>,
+}
+
+/// Totals over all processing cycles.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Counters {
+    pub runs: u64,
+    pub failed_runs: u64,
+    pub processed: u64,
+    pub routed: u64,
+    pub failed: u64,
+    /// Routed messages by action
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub actions: BTreeMap<String, u64>,
+}
+
+/// Outcome of one processing cycle.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub processed: u64,
+    pub routed: u64,
+    /// Messages whose processing failed
+    pub failed: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub actions: BTreeMap<String, u64>,
+    /// Why the cycle as a whole failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    pub fn new(run_id: String, started: DateTime<Utc>) -> Self {
+        Self {
+            run_id,
+            started,
+            finished: started,
+            processed: 0,
+            routed: 0,
+            failed: 0,
+            actions: BTreeMap::new(),
+            error: None,
+        }
+    }
+
+    pub fn count_action(&mut self, action: impl ToString) {
+        *self.actions.entry(action.to_string()).or_insert(0) += 1;
+    }
+}
+
+/// Messages seen for one address during a scan or processing cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sighting {
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub messages: u64,
+    pub sender_domains: BTreeMap<String, u64>,
+}
+
+impl Sighting {
+    pub fn new(at: DateTime<Utc>, sender_domain: Option<&str>) -> Self {
+        let mut sighting = Self {
+            first_seen: at,
+            last_seen: at,
+            messages: 0,
+            sender_domains: BTreeMap::new(),
+        };
+        sighting.add(at, sender_domain);
+        sighting
+    }
+
+    pub fn add(&mut self, at: DateTime<Utc>, sender_domain: Option<&str>) {
+        self.first_seen = self.first_seen.min(at);
+        self.last_seen = self.last_seen.max(at);
+        self.messages += 1;
+        if let Some(domain) = sender_domain {
+            *self.sender_domains.entry(domain.to_string()).or_insert(0) += 1;
+        }
+    }
+}
+
+impl AddressStats {
+    /// Sender domains ordered by message count, most frequent first.
+    pub fn top_sender_domains(&self) -> Vec<(&str, u64)> {
+        let mut domains: Vec<_> = self
+            .sender_domains
+            .iter()
+            .map(|(domain, &count)| (domain.as_str(), count))
+            .collect();
+        domains.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        domains
+    }
+
+    fn merge(&mut self, sighting: &Sighting) {
+        self.first_seen = Some(
+            self.first_seen
+                .map_or(sighting.first_seen, |t| t.min(sighting.first_seen)),
+        );
+        self.last_seen = Some(
+            self.last_seen
+                .map_or(sighting.last_seen, |t| t.max(sighting.last_seen)),
+        );
+        self.messages += sighting.messages;
+
+        for (domain, count) in &sighting.sender_domains {
+            *self.sender_domains.entry(domain.clone()).or_insert(0) += count;
+        }
+        if self.sender_domains.len() > MAX_SENDER_DOMAINS {
+            let keep: BTreeSet<String> = self
+                .top_sender_domains()
+                .into_iter()
+                .take(MAX_SENDER_DOMAINS)
+                .map(|(domain, _)| domain.to_string())
+                .collect();
+            self.sender_domains
+                .retain(|domain, _| keep.contains(domain));
+        }
+    }
+}
+
+impl State {
+    /// A missing file is an empty state.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path).context("Failed to read runtime state")?;
+        serde_json::from_str(&contents).context("Failed to parse runtime state")
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let contents =
+            serde_json::to_string_pretty(self).context("Failed to serialize runtime state")?;
+        write_atomic(path, &contents).context("Failed to write runtime state")
+    }
+
+    /// Locks the file, applies `f` to its current contents and saves the result.
+    pub fn update<P, F>(path: P, f: F) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut State),
+    {
+        let path = path.as_ref();
+        let _lock = ConfigLock::acquire(path)?;
+
+        let mut state = Self::load(path)?;
+        f(&mut state);
+        state.save(path)?;
+        Ok(state)
+    }
+
+    pub fn stats(&self, local_part: &str) -> Option<&AddressStats> {
+        self.addresses.get(local_part)
+    }
+
+    /// Merges a sighting into the statistics of an address.
+    pub fn record_sighting(&mut self, local_part: &str, sighting: &Sighting) {
+        self.addresses
+            .entry(local_part.to_string())
+            .or_default()
+            .merge(sighting);
+    }
+
+    pub fn record_history_id(&mut self, history_id: Option<u64>) {
+        self.history_id = self.history_id.max(history_id);
+    }
+
+    /// Adds a finished cycle to the counters and keeps it as the last run.
+    pub fn record_run(&mut self, summary: RunSummary) {
+        let counters = &mut self.counters;
+        counters.runs += 1;
+        if summary.error.is_some() {
+            counters.failed_runs += 1;
+        }
+        counters.processed += summary.processed;
+        counters.routed += summary.routed;
+        counters.failed += summary.failed;
+        for (action, count) in &summary.actions {
+            *counters.actions.entry(action.clone()).or_insert(0) += count;
+        }
+        self.last_run = Some(summary);
+    }
+}
+
+/// Runtime fields that older versions kept in routing.yaml.
+#[derive(Deserialize, Default)]
+struct LegacyRouting {
+    #[serde(default)]
+    updated_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    addresses: BTreeMap<String, serde_yaml::Value>,
+}
+
+impl LegacyRouting {
+    fn parse(contents: &str) -> Self {
+        serde_yaml::from_str(contents).unwrap_or_default()
+    }
+
+    /// Addresses written as mappings that still carry statistics.
+    fn stats(&self) -> Vec<(String, AddressStats)> {
+        self.addresses
+            .iter()
+            .filter(|(_, entry)| entry.is_mapping())
+            .filter_map(|(address, entry)| {
+                let stats: AddressStats = serde_yaml::from_value(entry.clone()).ok()?;
+                (stats != AddressStats::default()).then(|| (address.clone(), stats))
+            })
+            .collect()
+    }
+}
+
+/// Moves runtime state out of routing.yaml and checkpoint.json into the state file.
+/// Values already in the state file win. Returns true if anything was moved.
+pub fn migrate(routing_path: &Path, checkpoint_path: &Path, state_path: &Path) -> Result<bool> {
+    let _state_lock = ConfigLock::acquire(state_path)?;
+    let mut state = State::load(state_path)?;
+    let mut migrated = false;
+
+    if checkpoint_path.exists() {
+        let contents =
+            fs::read_to_string(checkpoint_path).context("Failed to read old checkpoint")?;
+        let checkpoint: Checkpoint =
+            serde_json::from_str(&contents).context("Failed to parse old checkpoint")?;
+        if state.checkpoint == Checkpoint::default() {
+            state.checkpoint = checkpoint;
+        }
+        migrated = true;
+    }
+
+    if routing_path.exists() {
+        let _routing_lock = ConfigLock::acquire(routing_path)?;
+        let contents =
+            fs::read_to_string(routing_path).context("Failed to read routing config file")?;
+        let legacy = LegacyRouting::parse(&contents);
+        let moved = legacy.stats();
+
+        if legacy.updated_date.is_some() || !moved.is_empty() {
+            state.scanned_until = state.scanned_until.or(legacy.updated_date);
+            for (address, stats) in &moved {
+                state
+                    .addresses
+                    .entry(address.clone())
+                    .or_insert_with(|| stats.clone());
+            }
+            // The state must be safe before routing.yaml forgets it
+            state.save(state_path)?;
+
+            let addresses: Vec<_> = moved.into_iter().map(|(address, _)| address).collect();
+            let yaml = strip_runtime_state(&contents, &addresses)?;
+            write_atomic(routing_path, &yaml).context("Failed to write routing config file")?;
+            migrated = true;
+        }
+    }
+
+    if migrated {
+        state.save(state_path)?;
+        if checkpoint_path.exists() {
+            fs::remove_file(checkpoint_path).context("Failed to remove old checkpoint")?;
+        }
+    }
+
+    Ok(migrated)
+}
+
+/// Parses a routing config, leaving out the runtime fields of older versions.
+fn without_runtime_state(contents: &str) -> Result<RoutingConfig> {
+    let mut doc: serde_yaml::Value =
+        serde_yaml::from_str(contents).context("Failed to parse routing config YAML")?;
+    if let Some(map) = doc.as_mapping_mut() {
+        map.remove("updated_date");
+        if let Some(addresses) = map.get_mut("addresses").and_then(|a| a.as_mapping_mut()) {
+            for (_, entry) in addresses.iter_mut() {
+                if let Some(entry) = entry.as_mapping_mut() {
+                    for field in ["first_seen", "last_seen", "messages", "sender_domains"] {
+                        entry.remove(field);
+                    }
+                }
+            }
+        }
+    }
+    serde_yaml::from_value(doc).context("Invalid routing config")
+}
+
+/// routing.yaml without `updated_date` and the per-address statistics in `moved`,
+/// keeping comments where possible.
+fn strip_runtime_state(contents: &str, moved: &[String]) -> Result<String> {
+    let config = without_runtime_state(contents)?;
+
+    let edited = (|| -> Result<String> {
+        let mut doc = YamlDocument::parse(contents);
+        doc.remove_top_level("updated_date")?;
+        for address in moved {
+            doc.set_entry(
+                "addresses",
+                address,
+                &serde_yaml::to_value(&config.addresses[address])?,
+            )?;
+        }
+        let yaml = doc.to_string();
+
+        let reparsed: RoutingConfig = serde_yaml::from_str(&yaml)?;
+        if reparsed != config {
+            anyhow::bail!("Edited routing config does not match the expected content");
+        }
+        Ok(yaml)
+    })();
+
+    match edited {
+        Ok(yaml) => Ok(yaml),
+        Err(e) => {
+            warn!(
+                "Rewriting routing config without preserving its layout: {:#}",
+                e
+            );
+            serde_yaml::to_string(&config).context("Failed to serialize routing config to YAML")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        format!("2024-01-{:02}T00:00:00Z", day).parse().unwrap()
+    }
+
+    #[test]
+    fn test_record_sighting() {
+        let mut state = State::default();
+
+        let mut sighting = Sighting::new(at(5), Some("shop.com"));
+        sighting.add(at(3), None);
+        state.record_sighting("shop", &sighting);
+        state.record_sighting("shop", &Sighting::new(at(7), Some("shop.com")));
+
+        let shop = state.stats("shop").unwrap();
+        assert_eq!(shop.first_seen, Some(at(3)));
+        assert_eq!(shop.last_seen, Some(at(7)));
+        assert_eq!(shop.messages, 3);
+        assert_eq!(shop.top_sender_domains(), vec![("shop.com", 2)]);
+        assert!(state.stats("other").is_none());
+    }
+
+    #[test]
+    fn test_sender_domains_are_capped() {
+        let mut state = State::default();
+
+        let mut sighting = Sighting::new(at(1), Some("frequent.com"));
+        sighting.add(at(1), Some("frequent.com"));
+        for i in 0..MAX_SENDER_DOMAINS + 5 {
+            sighting.add(at(1), Some(&format!("rare{:02}.com", i)));
+        }
+        state.record_sighting("shop", &sighting);
+
+        let stats = state.stats("shop").unwrap();
+        assert_eq!(stats.sender_domains.len(), MAX_SENDER_DOMAINS);
+        assert_eq!(stats.top_sender_domains()[0], ("frequent.com", 2));
+    }
+
+    #[test]
+    fn test_record_run() {
+        let mut state = State::default();
+
+        let mut summary = RunSummary::new("run1".to_string(), at(1));
+        summary.processed = 3;
+        summary.routed = 2;
+        summary.count_action("spam");
+        summary.count_action("spam");
+        state.record_run(summary);
+
+        let failed = RunSummary {
+            error: Some("offline".to_string()),
+            ..RunSummary::new("run2".to_string(), at(2))
+        };
+        state.record_run(failed);
+        state.record_history_id(Some(7));
+        state.record_history_id(None);
+
+        assert_eq!(state.counters.runs, 2);
+        assert_eq!(state.counters.failed_runs, 1);
+        assert_eq!(state.counters.processed, 3);
+        assert_eq!(state.counters.actions["spam"], 2);
+        assert_eq!(state.last_run.unwrap().run_id, "run2");
+        assert_eq!(state.history_id, Some(7));
+    }
+
+    #[test]
+    fn test_migrate_routing_config() {
+        let dir =
+            std::env::temp_dir().join(format!("gmail_router_test_state_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let routing_path = dir.join("routing.yaml");
+        let checkpoint_path = dir.join("checkpoint.json");
+        let state_path = dir.join("state.json");
+
+        fs::write(
+            &routing_path,
+            "# my aliases\naddresses:\n  plain: false\n  shop:  # Amazon\n    allowed: false\n    note: used for Amazon\n    first_seen: 2024-01-03T00:00:00Z\n    last_seen: 2024-01-07T00:00:00Z\n    messages: 3\n    sender_domains:\n      shop.com: 2\n  seen:\n    first_seen: 2024-01-05T00:00:00Z\n    last_seen: 2024-01-05T00:00:00Z\n    messages: 1\nupdated_date: 2024-02-01T00:00:00Z\n",
+        )
+        .unwrap();
+        fs::write(
+            &checkpoint_path,
+            r#"{"processed_until": "2024-02-02T00:00:00Z"}"#,
+        )
+        .unwrap();
+
+        assert!(migrate(&routing_path, &checkpoint_path, &state_path).unwrap());
+        assert!(!migrate(&routing_path, &checkpoint_path, &state_path).unwrap());
+
+        let routing = fs::read_to_string(&routing_path).unwrap();
+        let state = State::load(&state_path).unwrap();
+        let checkpoint_left = checkpoint_path.exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            routing,
+            "# my aliases\naddresses:\n  plain: false\n  shop: # Amazon\n    allowed: false\n    note: used for Amazon\n  seen: true\n"
+        );
+        assert!(!checkpoint_left);
+        assert_eq!(
+            state.scanned_until,
+            Some("2024-02-01T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            state.checkpoint.processed_until,
+            Some("2024-02-02T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(state.stats("shop").unwrap().messages, 3);
+        assert_eq!(state.stats("shop").unwrap().sender_domains["shop.com"], 2);
+        assert_eq!(state.stats("seen").unwrap().first_seen, Some(at(5)));
+        assert!(state.stats("plain").is_none());
+    }
+}
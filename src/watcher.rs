@@ -0,0 +1,89 @@
+use crate::config::RoutingConfig;
+use anyhow::{Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, warn};
+
+/// The last valid routing config, shared between the watcher and the processing loop.
+#[derive(Clone, Default)]
+pub struct SharedRoutingConfig {
+    inner: Arc<RwLock<Arc<RoutingConfig>>>,
+}
+
+impl SharedRoutingConfig {
+    pub fn new(config: RoutingConfig) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(RoutingConfig::load(path)?))
+    }
+
+    /// Snapshot of the current config; stays consistent while a message is processed.
+    pub fn current(&self) -> Arc<RoutingConfig> {
+        self.inner
+            .read()
+            .expect("routing config lock poisoned")
+            .clone()
+    }
+
+    pub fn replace(&self, config: RoutingConfig) {
+        *self.inner.write().expect("routing config lock poisoned") = Arc::new(config);
+    }
+
+    /// Reloads the config from `path`. An invalid file leaves the current config in place.
+    pub fn reload<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let config = RoutingConfig::load(path)?;
+        self.replace(config);
+        Ok(())
+    }
+}
+
+/// Reloads `shared` whenever the file at `path` changes.
+///
+/// Watches the parent directory rather than the file itself, since editors and
+/// `write_atomic` replace the file by renaming over it. Keep the returned watcher alive.
+pub fn watch_routing_config(
+    path: PathBuf,
+    shared: SharedRoutingConfig,
+) -> Result<RecommendedWatcher> {
+    let dir = path
+        .parent()
+        .context("Routing config has no parent directory")?
+        .to_path_buf();
+    let file_name = path.file_name().map(|n| n.to_owned());
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Routing config watcher error: {}", e);
+                return;
+            }
+        };
+
+        let touches_config = event
+            .paths
+            .iter()
+            .any(|p| p.file_name().map(|n| n.to_owned()) == file_name);
+        if !touches_config || event.kind.is_access() || !path.exists() {
+            return;
+        }
+
+        debug!("Routing config changed: {:?}", event.kind);
+        match shared.reload(&path) {
+            Ok(()) => info!("Routing config reloaded"),
+            Err(e) => warn!("Keeping previous routing config: {:#}", e),
+        }
+    })
+    .context("Failed to create routing config watcher")?;
+
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {:?}", dir))?;
+
+    Ok(watcher)
+}
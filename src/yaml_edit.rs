@@ -0,0 +1,573 @@
+//! Format-preserving edits of block-style YAML mappings.
+//!
+//! Handles the layout of the files this crate writes: top-level keys at column 0,
+//! each optionally holding one block mapping, or a block sequence that can be
+//! appended to. Comments, blank lines and the order of untouched entries are kept
+//! as they are. Anything else (flow collections, anchors) is reported as
+//! unsupported, and callers fall back to full serialization.
+
+use anyhow::{bail, Context, Result};
+use serde_yaml::Value;
+
+pub struct YamlDocument {
+    lines: Vec<String>,
+}
+
+struct Section {
+    header: usize,
+    /// One past the last line of the section body.
+    end: usize,
+    indent: usize,
+}
+
+impl YamlDocument {
+    pub fn parse(text: &str) -> Self {
+        Self {
+            lines: text.lines().map(str::to_string).collect(),
+        }
+    }
+
+    /// Sets a top-level scalar, keeping any trailing comment. Missing keys are appended.
+    pub fn set_top_level(&mut self, key: &str, value: &Value) -> Result<()> {
+        let found = self
+            .lines
+            .iter()
+            .position(|line| top_level_key(line).map(|(k, _)| k) == Some(key.to_string()));
+
+        match found {
+            Some(idx) => {
+                let nested = self.lines[idx + 1..]
+                    .iter()
+                    .find(|line| is_content(line))
+                    .is_some_and(|line| indent_of(line) > 0);
+                if nested {
+                    bail!("Top-level key {:?} is not a scalar", key);
+                }
+                let rendered = render_entry(key, value, 0)?;
+                if rendered.len() != 1 {
+                    bail!("Value of {:?} is not a scalar", key);
+                }
+                self.lines[idx] = replace_value(&self.lines[idx], 0, &rendered[0])?;
+            }
+            None => {
+                let rendered = render_entry(key, value, 0)?;
+                self.lines.extend(rendered);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Inserts a top-level scalar before the first key, below any leading comments.
+    pub fn prepend_top_level(&mut self, key: &str, value: &Value) -> Result<()> {
+        let rendered = render_entry(key, value, 0)?;
+        if rendered.len() != 1 {
+            bail!("Value of {:?} is not a scalar", key);
+        }
+        let at = self
+            .lines
+            .iter()
+            .position(|line| is_content(line))
+            .unwrap_or(self.lines.len());
+        self.lines.splice(at..at, rendered);
+        Ok(())
+    }
+
+    /// Removes a top-level scalar; a missing key is not an error.
+    pub fn remove_top_level(&mut self, key: &str) -> Result<()> {
+        let Some(idx) = self
+            .lines
+            .iter()
+            .position(|line| top_level_key(line).map(|(k, _)| k) == Some(key.to_string()))
+        else {
+            return Ok(());
+        };
+
+        let nested = self.lines[idx + 1..]
+            .iter()
+            .find(|line| is_content(line))
+            .is_some_and(|line| indent_of(line) > 0);
+        if nested {
+            bail!("Top-level key {:?} is not a scalar", key);
+        }
+        self.lines.remove(idx);
+        Ok(())
+    }
+
+    /// Sets `section.key`, replacing the existing entry in place or appending a new one
+    /// after the last entry of the section.
+    pub fn set_entry(&mut self, section: &str, key: &str, value: &Value) -> Result<()> {
+        let section = self.section(section, true)?.expect("section created");
+        let rendered = render_entry(key, value, section.indent)?;
+
+        match self.find_entry(&section, key)? {
+            Some((start, end)) if end - start == 1 && rendered.len() == 1 => {
+                self.lines[start] =
+                    replace_value(&self.lines[start], section.indent, &rendered[0])?;
+            }
+            Some((start, end)) => {
+                let mut rendered = rendered;
+                if let Some(comment) = entry_comment(&self.lines[start], section.indent) {
+                    if rendered[0].ends_with(':') {
+                        rendered[0] = format!("{} {}", rendered[0], comment);
+                    }
+                }
+                self.lines.splice(start..end, rendered);
+            }
+            None => {
+                let insert_at = self
+                    .last_content_line(&section)
+                    .map_or(section.header + 1, |i| i + 1);
+                self.lines.splice(insert_at..insert_at, rendered);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_entry(&mut self, section: &str, key: &str) -> Result<()> {
+        let Some(section) = self.section(section, false)? else {
+            return Ok(());
+        };
+        if let Some((start, end)) = self.find_entry(&section, key)? {
+            self.lines.drain(start..end);
+        }
+        Ok(())
+    }
+
+    /// Appends items to the top-level block sequence `key`, creating it if missing.
+    pub fn append_items(&mut self, key: &str, items: &[Value]) -> Result<()> {
+        let header = self
+            .lines
+            .iter()
+            .position(|line| top_level_key(line).map(|(k, _)| k) == Some(key.to_string()));
+        let header = match header {
+            Some(header) => header,
+            None => {
+                self.lines.push(format!("{}:", render_key(key)?));
+                self.lines.len() - 1
+            }
+        };
+
+        let (_, after_colon) = top_level_key(&self.lines[header]).expect("header is a key");
+        let inline = self.lines[header][after_colon..].trim_start().to_string();
+        let (inline_value, comment) = split_comment(&inline);
+        match inline_value.trim() {
+            "" => {}
+            "[]" => {
+                let head = &self.lines[header][..after_colon];
+                self.lines[header] = match comment {
+                    Some(comment) => format!("{} {}", head, comment),
+                    None => head.to_string(),
+                };
+            }
+            _ => bail!("{:?} is not a block sequence", key),
+        }
+
+        // Items of a sequence may start at column 0, so only keys end it
+        let end = self.lines[header + 1..]
+            .iter()
+            .position(|line| top_level_key(line).is_some())
+            .map_or(self.lines.len(), |i| header + 1 + i);
+        let indent = match self.lines[header + 1..end].iter().find(|l| is_content(l)) {
+            Some(line) if line.trim_start().starts_with('-') => indent_of(line),
+            Some(_) => bail!("{:?} is not a block sequence", key),
+            None => 2,
+        };
+
+        let text = serde_yaml::to_string(items).context("Failed to serialize items")?;
+        let prefix = " ".repeat(indent);
+        let rendered: Vec<_> = text
+            .lines()
+            .map(|line| format!("{}{}", prefix, line))
+            .collect();
+        let insert_at = (header + 1..end)
+            .rev()
+            .find(|&idx| is_content(&self.lines[idx]))
+            .map_or(header + 1, |i| i + 1);
+        self.lines.splice(insert_at..insert_at, rendered);
+
+        Ok(())
+    }
+
+    fn section(&mut self, name: &str, create: bool) -> Result<Option<Section>> {
+        let header = self
+            .lines
+            .iter()
+            .position(|line| top_level_key(line).map(|(k, _)| k) == Some(name.to_string()));
+
+        let header = match header {
+            Some(header) => header,
+            None if create => {
+                self.lines.push(format!("{}:", render_key(name)?));
+                self.lines.len() - 1
+            }
+            None => return Ok(None),
+        };
+
+        let (_, after_colon) = top_level_key(&self.lines[header]).expect("header is a key");
+        let inline = self.lines[header][after_colon..].trim_start().to_string();
+        let (inline_value, comment) = split_comment(&inline);
+        match inline_value.trim() {
+            "" => {}
+            "{}" => {
+                let head = &self.lines[header][..after_colon];
+                self.lines[header] = match comment {
+                    Some(comment) => format!("{} {}", head, comment),
+                    None => head.to_string(),
+                };
+            }
+            _ => bail!("Section {:?} is not a block mapping", name),
+        }
+
+        let end = self.lines[header + 1..]
+            .iter()
+            .position(|line| is_content(line) && indent_of(line) == 0)
+            .map_or(self.lines.len(), |i| header + 1 + i);
+
+        let indent = match self.lines[header + 1..end].iter().find(|l| is_content(l)) {
+            Some(line) if line.trim_start().starts_with('-') => {
+                bail!("Section {:?} is a sequence", name)
+            }
+            Some(line) => indent_of(line),
+            None => 2,
+        };
+
+        Ok(Some(Section {
+            header,
+            end,
+            indent,
+        }))
+    }
+
+    fn find_entry(&self, section: &Section, key: &str) -> Result<Option<(usize, usize)>> {
+        for idx in section.header + 1..section.end {
+            let line = &self.lines[idx];
+            if !is_content(line) || indent_of(line) != section.indent {
+                continue;
+            }
+            let Some((entry_key, _)) = parse_key(&line[section.indent..]) else {
+                bail!("Unsupported line in YAML: {:?}", line);
+            };
+            if entry_key != key {
+                continue;
+            }
+
+            let mut end = idx + 1;
+            for next in idx + 1..section.end {
+                let line = &self.lines[next];
+                if !is_content(line) {
+                    continue;
+                }
+                if indent_of(line) <= section.indent {
+                    break;
+                }
+                end = next + 1;
+            }
+            return Ok(Some((idx, end)));
+        }
+
+        Ok(None)
+    }
+
+    fn last_content_line(&self, section: &Section) -> Option<usize> {
+        (section.header + 1..section.end)
+            .rev()
+            .find(|&idx| is_content(&self.lines[idx]) && indent_of(&self.lines[idx]) > 0)
+    }
+}
+
+impl std::fmt::Display for YamlDocument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Whether the line carries data, as opposed to being blank or a comment.
+fn is_content(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    !trimmed.is_empty() && !trimmed.starts_with('#')
+}
+
+fn top_level_key(line: &str) -> Option<(String, usize)> {
+    if !is_content(line) || indent_of(line) != 0 || line.starts_with('-') {
+        return None;
+    }
+    parse_key(line)
+}
+
+/// Parses `key:` at the start of `s`; returns the key and the offset just past the colon.
+fn parse_key(s: &str) -> Option<(String, usize)> {
+    let token_end = match s.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let mut escaped = false;
+            let close = s[1..].char_indices().find(|&(_, c)| {
+                let found = c == quote && !escaped;
+                escaped = quote == '"' && c == '\\' && !escaped;
+                found
+            })?;
+            1 + close.0 + 1
+        }
+        _ => {
+            s.char_indices()
+                .find(|&(i, c)| c == ':' && matches!(s[i + 1..].chars().next(), None | Some(' ')))?
+                .0
+        }
+    };
+
+    if !s[token_end..].starts_with(':') {
+        return None;
+    }
+    let token = s[..token_end].trim_end();
+    let key = match serde_yaml::from_str::<Value>(token).ok()? {
+        Value::String(key) => key,
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        _ => return None,
+    };
+
+    Some((key, token_end + 1))
+}
+
+/// Splits `value  # comment` into the value and the comment, ignoring `#` inside quotes.
+fn split_comment(s: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut prev_space = true;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') if prev_space => return (s[..i].trim_end(), Some(&s[i..])),
+            _ => {}
+        }
+        prev_space = c.is_whitespace();
+    }
+    (s, None)
+}
+
+/// Replaces the value of a single-line entry with that of `rendered`, keeping the
+/// original key spelling and any trailing comment.
+fn replace_value(line: &str, indent: usize, rendered: &str) -> Result<String> {
+    let (_, after_colon) = parse_key(&line[indent..]).context("Not a key line")?;
+    let (_, new_after_colon) = parse_key(&rendered[indent..]).context("Not a key line")?;
+    let new_value = rendered[indent + new_after_colon..].trim();
+
+    let mut result = format!("{} {}", &line[..indent + after_colon], new_value);
+
+    let rest = line[indent + after_colon..].trim_start();
+    if let (_, Some(comment)) = split_comment(rest) {
+        let before = &rest[..rest.len() - comment.len()];
+        let spacing = (before.len() - before.trim_end().len()).max(1);
+        result.push_str(&" ".repeat(spacing));
+        result.push_str(comment);
+    }
+
+    Ok(result)
+}
+
+/// Trailing comment of a `key: value` line.
+fn entry_comment(line: &str, indent: usize) -> Option<String> {
+    let (_, after_colon) = parse_key(&line[indent..])?;
+    let (_, comment) = split_comment(line[indent + after_colon..].trim_start());
+    comment.map(str::to_string)
+}
+
+fn render_key(key: &str) -> Result<String> {
+    let key = serde_yaml::to_string(&Value::String(key.to_string()))
+        .context("Failed to serialize key")?;
+    Ok(key.trim_end().to_string())
+}
+
+fn render_entry(key: &str, value: &Value, indent: usize) -> Result<Vec<String>> {
+    let prefix = " ".repeat(indent);
+    let key = render_key(key)?;
+    let text = serde_yaml::to_string(value).context("Failed to serialize value")?;
+    let mut lines = text.lines();
+
+    let is_scalar = !matches!(value, Value::Mapping(_) | Value::Sequence(_));
+    let mut rendered = Vec::new();
+    match lines.next() {
+        Some(first) if is_scalar || first == "{}" || first == "[]" => {
+            rendered.push(format!("{}{}: {}", prefix, key, first));
+        }
+        Some(first) => {
+            rendered.push(format!("{}{}:", prefix, key));
+            rendered.push(format!("{}  {}", prefix, first));
+        }
+        None => rendered.push(format!("{}{}:", prefix, key)),
+    }
+    for line in lines {
+        rendered.push(format!("{}  {}", prefix, line));
+    }
+
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "\
+# Routing for example.com
+addresses:
+  # shops
+  shop: false  # leaked
+  news: true
+
+  bank: true
+# last scan
+updated_date: 2024-01-01T00:00:00Z
+";
+
+    #[test]
+    fn test_set_existing_entry_keeps_comments() {
+        let mut doc = YamlDocument::parse(DOC);
+        doc.set_entry("addresses", "shop", &Value::Bool(true))
+            .unwrap();
+
+        assert_eq!(
+            doc.to_string(),
+            DOC.replace("shop: false  # leaked", "shop: true  # leaked")
+        );
+
+        let mut map = serde_yaml::Mapping::new();
+        map.insert(Value::String("allowed".to_string()), Value::Bool(false));
+        doc.set_entry("addresses", "shop", &Value::Mapping(map))
+            .unwrap();
+        assert!(doc
+            .to_string()
+            .contains("  shop: # leaked\n    allowed: false\n  news: true\n"));
+    }
+
+    #[test]
+    fn test_append_entry_after_last() {
+        let mut doc = YamlDocument::parse(DOC);
+        doc.set_entry("addresses", "zoo", &Value::Bool(true))
+            .unwrap();
+
+        assert_eq!(
+            doc.to_string(),
+            DOC.replace("  bank: true\n", "  bank: true\n  zoo: true\n")
+        );
+    }
+
+    #[test]
+    fn test_set_top_level() {
+        let mut doc = YamlDocument::parse(DOC);
+        doc.set_top_level(
+            "updated_date",
+            &Value::String("2025-01-01T00:00:00Z".to_string()),
+        )
+        .unwrap();
+        doc.set_top_level("version", &Value::Number(1.into()))
+            .unwrap();
+
+        let text = doc.to_string();
+        assert!(text.contains("updated_date: 2025-01-01T00:00:00Z\nversion: 1\n"));
+        assert!(text.starts_with("# Routing for example.com\n"));
+    }
+
+    #[test]
+    fn test_append_items() {
+        let item = |name: &str| {
+            let mut map = serde_yaml::Mapping::new();
+            map.insert(Value::from("name"), Value::from(name));
+            map.insert(Value::from("action"), Value::from("spam"));
+            Value::Mapping(map)
+        };
+
+        let text = "rules:\n  # first\n  - name: a\n    action: trash\n\n# done\nversion: 1\n";
+        let mut doc = YamlDocument::parse(text);
+        doc.append_items("rules", &[item("b")]).unwrap();
+        assert_eq!(
+            doc.to_string(),
+            text.replace(
+                "action: trash\n",
+                "action: trash\n  - name: b\n    action: spam\n"
+            )
+        );
+
+        let mut doc = YamlDocument::parse("rules: []\n");
+        doc.append_items("rules", &[item("a")]).unwrap();
+        assert_eq!(doc.to_string(), "rules:\n  - name: a\n    action: spam\n");
+
+        let mut doc = YamlDocument::parse("rules:\n- name: a\nversion: 1\n");
+        doc.append_items("rules", &[item("b")]).unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "rules:\n- name: a\n- name: b\n  action: spam\nversion: 1\n"
+        );
+
+        let mut doc = YamlDocument::parse("rules:\n  a: b\n");
+        assert!(doc.append_items("rules", &[item("c")]).is_err());
+    }
+
+    #[test]
+    fn test_remove_top_level() {
+        let mut doc = YamlDocument::parse(DOC);
+        doc.remove_top_level("updated_date").unwrap();
+        doc.remove_top_level("missing").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            DOC.replace("updated_date: 2024-01-01T00:00:00Z\n", "")
+        );
+
+        assert!(doc.remove_top_level("addresses").is_err());
+    }
+
+    #[test]
+    fn test_prepend_top_level() {
+        let mut doc = YamlDocument::parse(DOC);
+        doc.prepend_top_level("version", &Value::Number(1.into()))
+            .unwrap();
+        assert_eq!(
+            doc.to_string(),
+            DOC.replace("addresses:\n", "version: 1\naddresses:\n")
+        );
+    }
+
+    #[test]
+    fn test_new_and_empty_sections() {
+        let mut doc = YamlDocument::parse("addresses: {}  # none yet\n");
+        doc.set_entry("addresses", "a", &Value::Bool(true)).unwrap();
+        doc.set_entry("actions", "a", &Value::String("spam".to_string()))
+            .unwrap();
+
+        assert_eq!(
+            doc.to_string(),
+            "addresses: # none yet\n  a: true\nactions:\n  a: spam\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_and_replace_block_entry() {
+        let mut doc = YamlDocument::parse("addresses:\n  a:\n    x: 1\n  b: true\n");
+        let mut map = serde_yaml::Mapping::new();
+        map.insert(Value::String("y".to_string()), Value::Number(2.into()));
+        doc.set_entry("addresses", "a", &Value::Mapping(map))
+            .unwrap();
+        assert_eq!(doc.to_string(), "addresses:\n  a:\n    y: 2\n  b: true\n");
+
+        doc.set_entry("addresses", "b", &Value::Mapping(Default::default()))
+            .unwrap();
+        doc.set_entry("addresses", "b", &Value::Bool(false))
+            .unwrap();
+        assert_eq!(doc.to_string(), "addresses:\n  a:\n    y: 2\n  b: false\n");
+
+        doc.remove_entry("addresses", "a").unwrap();
+        assert_eq!(doc.to_string(), "addresses:\n  b: false\n");
+    }
+
+    #[test]
+    fn test_unsupported_layout() {
+        let mut doc = YamlDocument::parse("addresses: {a: true}\n");
+        assert!(doc.set_entry("addresses", "b", &Value::Bool(true)).is_err());
+    }
+}